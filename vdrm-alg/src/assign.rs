@@ -0,0 +1,198 @@
+//! Which screens light a voxel that several screens can reach.
+
+use crate::{AngleMap, Codec, PixelSurface, NUM_SCREENS, W_PIXELS};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ScreenPolicy {
+    /// Every screen that reaches the voxel lights it.
+    #[default]
+    All,
+    /// Only the screen with the best [`screen_score`].
+    BestSingle,
+    /// One screen per voxel, chosen greedily to keep the busiest screen's line
+    /// count low. Voxels sharing a line that is already lit are free.
+    LoadBalanced,
+}
+
+/// Outcome of a screen assignment, see [`Codec::encode_with`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssignStats {
+    /// Voxels lit by each screen.
+    pub voxels: [usize; NUM_SCREENS],
+    /// `ScreenLine`s of each screen over all angles.
+    pub lines: [usize; NUM_SCREENS],
+    /// Voxels lit by more than one screen.
+    pub shared: usize,
+    /// Voxels no screen can reach.
+    pub unreachable: usize,
+}
+
+impl AssignStats {
+    pub(crate) fn new(masks: &[[bool; NUM_SCREENS]], angle_map: &AngleMap) -> Self {
+        let mut stats = Self::default();
+        for mask in masks {
+            let count = mask.iter().filter(|&&m| m).count();
+            match count {
+                0 => stats.unreachable += 1,
+                1 => {}
+                _ => stats.shared += 1,
+            }
+            for (voxels, _) in stats.voxels.iter_mut().zip(mask).filter(|(_, &m)| m) {
+                *voxels += 1;
+            }
+        }
+        for lines_arr in angle_map.values() {
+            for (count, lines) in stats.lines.iter_mut().zip(lines_arr) {
+                *count += lines.len();
+            }
+        }
+        stats
+    }
+
+    /// Line count of the busiest screen.
+    pub fn max_lines(&self) -> usize {
+        self.lines.iter().copied().max().unwrap_or(0)
+    }
+}
+
+/// Normal of the mirror facet at `angle` in screen space.
+fn mirror_normal(codec: &Codec, angle: u32) -> glam::Vec3 {
+    // the linear part of a reflection is I - 2 n n^T
+    let linear = glam::Mat3::from_mat4(codec.mirror_mat(angle));
+    let diff = glam::Mat3::IDENTITY - linear;
+    [diff.x_axis, diff.y_axis, diff.z_axis]
+        .into_iter()
+        .max_by(|a, b| a.length_squared().total_cmp(&b.length_squared()))
+        .unwrap()
+        .normalize()
+}
+
+/// How well `screen_idx` shows voxel `(x, y, z)`, in 0..=1, `None` if the
+/// screen can't reach it. The product of how squarely the screen faces the
+/// mirror at the voxel's angle and how close the pixel is to the panel centre.
+pub fn screen_score(codec: &Codec, screen_idx: usize, x: u32, y: u32, z: u32) -> Option<f32> {
+    let z_info = codec.z_info(screen_idx, x, y, z)?;
+    let [p_o, p_z, _, p_y] = codec.geometry().screens[screen_idx]
+        .points
+        .map(glam::Vec3::from);
+    let normal = (p_z - p_o).cross(p_y - p_o).normalize();
+    let facing = normal.dot(mirror_normal(codec, z_info.angle)).abs();
+    let half = W_PIXELS as f32 / 2.;
+    let offset = glam::Vec2::new(
+        z_info.screen_pixel.pixel as f32 + 0.5 - half,
+        z_info.screen_pixel.addr as f32 + 0.5 - half,
+    );
+    let centre = 1. - offset.length() / (half * std::f32::consts::SQRT_2);
+    Some(facing * centre)
+}
+
+/// Screens lighting each voxel of `surface`, in surface order.
+pub fn assign(
+    codec: &Codec,
+    surface: &PixelSurface,
+    policy: ScreenPolicy,
+) -> Vec<[bool; NUM_SCREENS]> {
+    let scores: Vec<[Option<f32>; NUM_SCREENS]> = surface
+        .iter()
+        .map(|&(x, y, (z, _))| std::array::from_fn(|idx| screen_score(codec, idx, x, y, z)))
+        .collect();
+    let best = |scores: &[Option<f32>; NUM_SCREENS]| {
+        (0..NUM_SCREENS)
+            .filter_map(|idx| Some((idx, scores[idx]?)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(idx, _)| idx)
+    };
+    let single = |idx: Option<usize>| {
+        let mut mask = [false; NUM_SCREENS];
+        if let Some(idx) = idx {
+            mask[idx] = true;
+        }
+        mask
+    };
+    match policy {
+        ScreenPolicy::All => scores.iter().map(|s| s.map(|s| s.is_some())).collect(),
+        ScreenPolicy::BestSingle => scores.iter().map(|s| single(best(s))).collect(),
+        ScreenPolicy::LoadBalanced => {
+            // voxels with fewer choices first, they constrain the others
+            let mut order: Vec<usize> = (0..surface.len()).collect();
+            order.sort_by_key(|&i| scores[i].iter().filter(|s| s.is_some()).count());
+            let mut lit: BTreeSet<(usize, u32, u32)> = BTreeSet::new();
+            let mut line_counts = [0usize; NUM_SCREENS];
+            let mut masks = vec![[false; NUM_SCREENS]; surface.len()];
+            for i in order {
+                let (x, y, (z, _)) = surface[i];
+                let candidates: Vec<(usize, (usize, u32, u32), f32)> = (0..NUM_SCREENS)
+                    .filter_map(|idx| {
+                        let score = scores[i][idx]?;
+                        let z_info = codec.z_info(idx, x, y, z)?;
+                        Some((idx, (idx, z_info.angle, z_info.screen_pixel.addr), score))
+                    })
+                    .collect();
+                let chosen = candidates
+                    .iter()
+                    .filter(|c| lit.contains(&c.1))
+                    .max_by(|a, b| a.2.total_cmp(&b.2))
+                    .or_else(|| {
+                        candidates.iter().min_by(|a, b| {
+                            line_counts[a.0]
+                                .cmp(&line_counts[b.0])
+                                .then(b.2.total_cmp(&a.2))
+                        })
+                    });
+                let Some(&(idx, line, _)) = chosen else {
+                    continue;
+                };
+                if lit.insert(line) {
+                    line_counts[idx] += 1;
+                }
+                masks[i][idx] = true;
+            }
+            masks
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Rgb;
+    use crate::driver::PlainShiftRegister;
+    use crate::{EncodeOptions, H_PIXELS};
+
+    #[test]
+    fn test_policies() {
+        let codec = Codec::new();
+        let mut surface = PixelSurface::new();
+        for x in (0..W_PIXELS as u32).step_by(2) {
+            for y in (0..W_PIXELS as u32).step_by(2) {
+                for z in (0..H_PIXELS as u32).step_by(4) {
+                    surface.push((x, y, (z, Rgb::WHITE)));
+                }
+            }
+        }
+        let encode = |screen_policy| {
+            let options = EncodeOptions {
+                screen_policy,
+                ..EncodeOptions::new(&PlainShiftRegister)
+            };
+            let (angle_map, stats) = codec.encode_with(&surface, &options);
+            (angle_map, stats.assign)
+        };
+        let (all_map, all) = encode(ScreenPolicy::All);
+        assert_eq!(all_map, codec.encode(&surface, &PlainShiftRegister));
+        assert!(all.shared > 0);
+        let reachable = surface.len() - all.unreachable;
+
+        for policy in [ScreenPolicy::BestSingle, ScreenPolicy::LoadBalanced] {
+            let (_, stats) = encode(policy);
+            assert_eq!(stats.shared, 0, "{policy:?}");
+            assert_eq!(stats.unreachable, all.unreachable);
+            assert_eq!(stats.voxels.iter().sum::<usize>(), reachable);
+            assert!(stats.max_lines() <= all.max_lines(), "{policy:?} {stats:?}");
+        }
+        let (_, best) = encode(ScreenPolicy::BestSingle);
+        let (_, balanced) = encode(ScreenPolicy::LoadBalanced);
+        assert!(balanced.max_lines() <= best.max_lines());
+    }
+}
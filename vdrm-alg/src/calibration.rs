@@ -0,0 +1,68 @@
+//! Test patterns for matching the screens' white point and brightness.
+//!
+//! Encode the same voxels through one screen at a time and compare the
+//! frames side by side, then adjust `Geometry::calibration` until they match.
+
+use crate::driver::LedDriverProfile;
+use crate::{AngleMap, Codec, PixelColor, PixelSurface, H_PIXELS, NUM_SCREENS, W_PIXELS};
+
+/// Voxels on a grid of `step` pixels that every screen can light.
+pub fn common_voxels(codec: &Codec, step: u32, color: PixelColor) -> PixelSurface {
+    let step = step.max(1) as usize;
+    let mut surface = PixelSurface::new();
+    for x in (0..W_PIXELS as u32).step_by(step) {
+        for y in (0..W_PIXELS as u32).step_by(step) {
+            for z in (0..H_PIXELS as u32).step_by(step) {
+                if (0..NUM_SCREENS).all(|idx| codec.z_info(idx, x, y, z).is_some()) {
+                    surface.push((x, y, (z, color)));
+                }
+            }
+        }
+    }
+    surface
+}
+
+/// One frame per screen, frame `i` lights `surface` through screen `i` only.
+pub fn screen_patterns(
+    codec: &Codec,
+    surface: &PixelSurface,
+    driver: &dyn LedDriverProfile,
+) -> [AngleMap; NUM_SCREENS] {
+    std::array::from_fn(|screen_idx| {
+        let mut mask = [false; NUM_SCREENS];
+        mask[screen_idx] = true;
+        codec.encode_screens(surface, mask, driver)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::{Rgb, ScreenCalibration};
+    use crate::driver::PlainShiftRegister;
+    use crate::geometry::Geometry;
+
+    #[test]
+    fn test_screen_patterns() {
+        let mut geometry = Geometry::default();
+        geometry.calibration[1] = ScreenCalibration::with_gain(0.5);
+        let codec = Codec::with_geometry(geometry);
+        let surface = common_voxels(&codec, 4, Rgb::WHITE);
+        assert!(!surface.is_empty());
+        let patterns = screen_patterns(&codec, &surface, &PlainShiftRegister);
+        for (screen_idx, pattern) in patterns.iter().enumerate() {
+            assert!(!pattern.is_empty());
+            let expected = match screen_idx {
+                1 => Rgb::new(128, 128, 128),
+                _ => Rgb::WHITE,
+            };
+            for lines_arr in pattern.values() {
+                for (idx, lines) in lines_arr.iter().enumerate() {
+                    assert_eq!(lines.is_empty(), idx != screen_idx);
+                    let colors = lines.iter().flat_map(|l| l.pixels.iter().flatten());
+                    assert!(colors.copied().all(|c| c == expected));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! Several voxels landing on one pixel column of a screen at one angle.
+//!
+//! A column can only light one line, so all but one voxel are dropped, or
+//! with [`CollisionRule::Blend`] merged into one color.
+
+use crate::PixelColor;
+
+pub type Voxel = (u32, u32, u32);
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum CollisionRule {
+    /// Keep the lowest line address, of voxels on the same pixel the last
+    /// one encoded.
+    #[default]
+    FirstAddr,
+    /// Keep the voxel nearest to a viewer at this position in voxel units.
+    NearestViewer([f32; 3]),
+    /// Keep the voxel with the highest channel sum.
+    Brightest,
+    /// Keep the voxel with the smallest z, z counts down from the top of the
+    /// volume.
+    FrontZ,
+    /// Light the lowest address with the mean color of all the voxels.
+    Blend,
+}
+
+/// Voxels that collided in one encode.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct CollisionStats {
+    /// Times two voxels met on one pixel column.
+    pub collisions: usize,
+    /// Voxels not shown, always 0 for [`CollisionRule::Blend`].
+    pub dropped: usize,
+}
+
+/// The voxel lighting a pixel, `hits` voxels merged into it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct PixelSource {
+    pub voxel: Voxel,
+    pub addr: u32,
+    pub color: PixelColor,
+    pub hits: u32,
+}
+
+impl PixelSource {
+    pub fn new(voxel: Voxel, addr: u32, color: PixelColor) -> Self {
+        Self {
+            voxel,
+            addr,
+            color,
+            hits: 1,
+        }
+    }
+}
+
+impl CollisionRule {
+    /// Higher wins.
+    fn score(&self, source: &PixelSource) -> f32 {
+        let (x, y, z) = source.voxel;
+        match self {
+            CollisionRule::NearestViewer(viewer) => {
+                let d = glam::Vec3::new(x as f32, y as f32, z as f32) - glam::Vec3::from(*viewer);
+                -d.length_squared()
+            }
+            CollisionRule::Brightest => source.color.channels().iter().map(|&v| v as f32).sum(),
+            CollisionRule::FrontZ => -(z as f32),
+            CollisionRule::FirstAddr | CollisionRule::Blend => 0.,
+        }
+    }
+
+    /// Resolve `new` arriving at a pixel column already lit by `old`.
+    pub(crate) fn merge(
+        &self,
+        old: PixelSource,
+        new: PixelSource,
+        stats: &mut CollisionStats,
+    ) -> PixelSource {
+        stats.collisions += 1;
+        let keep_new = match self {
+            CollisionRule::FirstAddr => new.addr <= old.addr,
+            CollisionRule::Blend => {
+                let hits = old.hits + new.hits;
+                let channels = std::array::from_fn(|ch| {
+                    let sum = old.color.channels()[ch] as u32 * old.hits
+                        + new.color.channels()[ch] as u32 * new.hits;
+                    ((sum + hits / 2) / hits) as u8
+                });
+                let first = if new.addr < old.addr { new } else { old };
+                return PixelSource {
+                    color: PixelColor::from_channels(channels),
+                    hits,
+                    ..first
+                };
+            }
+            // ties go to the smaller voxel so the result is order independent
+            _ => self
+                .score(&new)
+                .total_cmp(&self.score(&old))
+                .then(old.voxel.cmp(&new.voxel))
+                .is_gt(),
+        };
+        let (kept, dropped) = if keep_new { (new, old) } else { (old, new) };
+        stats.dropped += dropped.hits as usize;
+        kept
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Rgb;
+    use crate::driver::PlainShiftRegister;
+    use crate::incremental::IncrementalEncoder;
+    use crate::{Codec, EncodeOptions, PixelSurface, H_PIXELS, W_PIXELS};
+
+    #[test]
+    fn test_merge() {
+        let low = PixelSource::new((1, 1, 9), 2, Rgb::new(200, 0, 0));
+        let high = PixelSource::new((5, 5, 3), 7, Rgb::new(0, 0, 100));
+        let winner = |rule: CollisionRule| {
+            let mut stats = CollisionStats::default();
+            let a = rule.merge(low, high, &mut stats);
+            let b = rule.merge(high, low, &mut stats);
+            assert_eq!(a, b, "{rule:?}");
+            assert_eq!(stats.dropped, 2);
+            a.voxel
+        };
+        assert_eq!(winner(CollisionRule::FirstAddr), low.voxel);
+        assert_eq!(winner(CollisionRule::Brightest), low.voxel);
+        assert_eq!(winner(CollisionRule::FrontZ), high.voxel);
+        assert_eq!(
+            winner(CollisionRule::NearestViewer([9., 9., 0.])),
+            high.voxel
+        );
+        assert_eq!(
+            winner(CollisionRule::NearestViewer([0., 0., 9.])),
+            low.voxel
+        );
+
+        let mut stats = CollisionStats::default();
+        let blend = CollisionRule::Blend.merge(high, low, &mut stats);
+        assert_eq!((blend.addr, blend.hits), (2, 2));
+        assert_eq!(blend.color, Rgb::new(100, 0, 50));
+        let third = PixelSource::new((0, 0, 0), 4, Rgb::new(0, 0, 0));
+        let blend = CollisionRule::Blend.merge(blend, third, &mut stats);
+        assert_eq!(blend.color, Rgb::new(67, 0, 33));
+        assert_eq!(
+            stats,
+            CollisionStats {
+                collisions: 2,
+                dropped: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_encode_collisions() {
+        let codec = Codec::new();
+        let mut surface = PixelSurface::new();
+        for x in 0..W_PIXELS as u32 {
+            for y in 0..W_PIXELS as u32 {
+                for z in (0..H_PIXELS as u32).step_by(8) {
+                    surface.push((x, y, (z, Rgb::new(x as u8 * 4, y as u8 * 4, z as u8))));
+                }
+            }
+        }
+        let lit = |angle_map: &crate::AngleMap| {
+            angle_map
+                .values()
+                .flatten()
+                .flatten()
+                .map(|l| l.pixels.iter().flatten().count())
+                .sum::<usize>()
+        };
+        let first = codec.encode(&surface, &PlainShiftRegister);
+        for collision in [
+            CollisionRule::FirstAddr,
+            CollisionRule::NearestViewer([32., -40., 0.]),
+            CollisionRule::Brightest,
+            CollisionRule::FrontZ,
+            CollisionRule::Blend,
+        ] {
+            let options = EncodeOptions {
+                collision,
+                ..EncodeOptions::new(&PlainShiftRegister)
+            };
+            let (angle_map, stats) = codec.encode_with(&surface, &options);
+            let stats = stats.collisions;
+            assert!(stats.collisions > 0);
+            match collision {
+                CollisionRule::Blend => assert_eq!(stats.dropped, 0),
+                _ => assert_eq!(stats.dropped, stats.collisions),
+            }
+            // every rule lights the same pixel columns
+            assert_eq!(lit(&angle_map), lit(&first), "{collision:?}");
+
+            let mut encoder = IncrementalEncoder::with_options(&codec, options);
+            encoder.apply(
+                surface
+                    .iter()
+                    .map(|&(x, y, (z, color))| crate::incremental::VoxelOp::Insert {
+                        x,
+                        y,
+                        z,
+                        color,
+                    }),
+            );
+            assert_eq!(*encoder.angle_map(), angle_map, "{collision:?}");
+            assert_eq!(encoder.collisions(), stats);
+        }
+    }
+
+    #[test]
+    fn test_encode_winners() {
+        let codec = Codec::new();
+        // two voxels on one column of screen 0 on different lines
+        let mut columns = std::collections::BTreeMap::new();
+        let mut pair = None;
+        'search: for x in 0..W_PIXELS as u32 {
+            for y in 0..W_PIXELS as u32 {
+                for z in 0..H_PIXELS as u32 {
+                    let Some(info) = codec.z_info(0, x, y, z) else {
+                        continue;
+                    };
+                    let pixel = info.screen_pixel;
+                    let column = (info.angle, pixel.pixel);
+                    let source = PixelSource::new((x, y, z), pixel.addr, Rgb::BLACK);
+                    if let Some(old) = columns.insert(column, source) {
+                        if old.addr != source.addr && old.voxel.2 != z {
+                            pair = Some((column, old, source));
+                            break 'search;
+                        }
+                    }
+                }
+            }
+        }
+        let ((angle, pixel), a, b) = pair.unwrap();
+        let (low, high) = if a.addr < b.addr { (a, b) } else { (b, a) };
+        let front = if a.voxel.2 < b.voxel.2 { a } else { b };
+        // the line further out is the brighter one
+        let (low_color, high_color) = (Rgb::new(0, 0, 0x40), Rgb::new(0xff, 0x80, 0));
+        let color_of = |source: PixelSource| match source.addr == low.addr {
+            true => low_color,
+            false => high_color,
+        };
+        let surface = vec![
+            (low.voxel.0, low.voxel.1, (low.voxel.2, low_color)),
+            (high.voxel.0, high.voxel.1, (high.voxel.2, high_color)),
+        ];
+        let voxel_pos = |v: Voxel| [v.0 as f32, v.1 as f32, v.2 as f32];
+        for (collision, addr, color) in [
+            (CollisionRule::FirstAddr, low.addr, low_color),
+            (CollisionRule::Brightest, high.addr, high_color),
+            (CollisionRule::FrontZ, front.addr, color_of(front)),
+            (
+                CollisionRule::NearestViewer(voxel_pos(high.voxel)),
+                high.addr,
+                high_color,
+            ),
+            (
+                CollisionRule::NearestViewer(voxel_pos(low.voxel)),
+                low.addr,
+                low_color,
+            ),
+            (CollisionRule::Blend, low.addr, Rgb::new(0x80, 0x40, 0x20)),
+        ] {
+            let options = EncodeOptions {
+                collision,
+                ..EncodeOptions::new(&PlainShiftRegister)
+            };
+            let (angle_map, stats) = codec.encode_with(&surface, &options);
+            let lit: Vec<_> = angle_map[&angle][0]
+                .iter()
+                .filter_map(|line| Some((line.addr, line.pixels[pixel as usize]?)))
+                .collect();
+            assert_eq!(lit, [(addr, color)], "{collision:?}");
+            assert!(stats.collisions.collisions >= 1);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use crate::AngleMap;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(0xff, 0xff, 0xff);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// From `0xRRGGBB`, the top byte is ignored.
+    pub const fn from_rgb24(v: u32) -> Self {
+        Self::new((v >> 16) as u8, (v >> 8) as u8, v as u8)
+    }
+
+    pub const fn to_rgb24(self) -> u32 {
+        (self.r as u32) << 16 | (self.g as u32) << 8 | self.b as u32
+    }
+
+    pub fn is_black(self) -> bool {
+        self == Self::BLACK
+    }
+
+    pub fn channels(self) -> [u8; 3] {
+        [self.r, self.g, self.b]
+    }
+
+    pub fn from_channels([r, g, b]: [u8; 3]) -> Self {
+        Self::new(r, g, b)
+    }
+
+    /// Every channel times `factor`, rounded and saturated.
+    pub fn scale(self, factor: f32) -> Self {
+        Self::from_channels(
+            self.channels()
+                .map(|v| (v as f32 * factor).round().clamp(0., 255.) as u8),
+        )
+    }
+}
+
+/// Per screen color correction, `gain * matrix * [r, g, b]` with channels in
+/// 0..=255. Stored in `Geometry` and applied by `Codec::encode`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScreenCalibration {
+    /// Row major, row `i` gives output channel `i`.
+    pub matrix: [[f32; 3]; 3],
+    pub gain: f32,
+}
+
+impl Default for ScreenCalibration {
+    fn default() -> Self {
+        Self {
+            matrix: [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+            gain: 1.,
+        }
+    }
+}
+
+impl ScreenCalibration {
+    pub fn with_gain(gain: f32) -> Self {
+        Self {
+            gain,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn apply(&self, color: Rgb) -> Rgb {
+        if self.is_identity() {
+            return color;
+        }
+        let input = color.channels().map(|v| v as f32);
+        let channels = self.matrix.map(|row| {
+            let v: f32 = row.iter().zip(&input).map(|(m, v)| m * v).sum();
+            (v * self.gain).round().clamp(0., 255.) as u8
+        });
+        Rgb::from_channels(channels)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Dither {
+    #[default]
+    None,
+    /// 4x4 Bayer threshold, every 16 angles an LED goes through all of it.
+    Ordered,
+    /// Quantization error of an LED carried to the next angle it is lit at.
+    ErrorDiffusion,
+}
+
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Color stage between `Codec::encode` and the output backend: per channel
+/// gamma, global brightness and reduction to the driver's color depth.
+///
+/// Reduced colors keep the 8-bit range, a `depth` bit level `l` becomes
+/// `l * 255 / (2^depth - 1)`, so the top `depth` bits hold the level.
+#[derive(Debug, Clone)]
+pub struct ColorPipeline {
+    luts: [[u8; 256]; 3],
+    /// Bits per channel after reduction, drivers commonly use 1, 3, 4 or 6.
+    pub depth: u8,
+    pub dither: Dither,
+}
+
+impl Default for ColorPipeline {
+    fn default() -> Self {
+        Self::new([1.0; 3], 1.0, 8, Dither::None)
+    }
+}
+
+impl ColorPipeline {
+    pub fn new(gamma: [f32; 3], brightness: f32, depth: u8, dither: Dither) -> Self {
+        let luts = gamma.map(|gamma| {
+            std::array::from_fn(|v| {
+                let v = (v as f32 / 255.).powf(gamma) * brightness.clamp(0., 1.);
+                (v * 255.).round() as u8
+            })
+        });
+        Self {
+            luts,
+            depth: depth.clamp(1, 8),
+            dither,
+        }
+    }
+
+    pub fn lut(&self, channel: usize) -> &[u8; 256] {
+        &self.luts[channel]
+    }
+
+    fn quantize(&self, v: f32, threshold: f32) -> u8 {
+        let max = ((1u32 << self.depth) - 1) as f32;
+        let level = (v * max / 255. + threshold).floor().clamp(0., max);
+        (level * 255. / max).round() as u8
+    }
+
+    /// Gamma, brightness and reduction of one pixel. `err` is the error
+    /// carried over from the previous angle the LED was lit at.
+    fn map_color(&self, color: Rgb, angle: u32, idx: usize, err: &mut [f32; 3]) -> Rgb {
+        let mut channels = color.channels();
+        for (ch, v) in channels.iter_mut().enumerate() {
+            *v = self.luts[ch][*v as usize];
+        }
+        if self.depth == 8 {
+            return Rgb::from_channels(channels);
+        }
+        let channels = match self.dither {
+            Dither::None => channels.map(|v| self.quantize(v as f32, 0.5)),
+            Dither::Ordered => {
+                let a = angle as usize;
+                let t = BAYER_4X4[a % 4][(a / 4 + idx) % 4] as f32;
+                channels.map(|v| self.quantize(v as f32, (t + 0.5) / 16.))
+            }
+            Dither::ErrorDiffusion => {
+                let mut out = [0; 3];
+                for ch in 0..3 {
+                    let v = channels[ch] as f32 + err[ch];
+                    out[ch] = self.quantize(v, 0.5);
+                    err[ch] = v - out[ch] as f32;
+                }
+                out
+            }
+        };
+        Rgb::from_channels(channels)
+    }
+
+    /// Run every lit pixel of the frame through the pipeline, angles in
+    /// ascending order.
+    pub fn apply(&self, angle_map: &mut AngleMap) {
+        let mut errors: BTreeMap<(usize, u32, usize), [f32; 3]> = BTreeMap::new();
+        let mut no_error = [0f32; 3];
+        for (&angle, lines_arr) in angle_map.iter_mut() {
+            for (screen_idx, lines) in lines_arr.iter_mut().enumerate() {
+                for line in lines {
+                    let addr = line.addr;
+                    for (idx, pixel) in line.pixels.iter_mut().enumerate() {
+                        let Some(color) = pixel else {
+                            continue;
+                        };
+                        let err = match self.dither {
+                            Dither::ErrorDiffusion => {
+                                errors.entry((screen_idx, addr, idx)).or_default()
+                            }
+                            _ => &mut no_error,
+                        };
+                        *color = self.map_color(*color, angle, idx, err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ScreenLine, W_PIXELS};
+
+    fn gray_frame(v: u8, angles: u32) -> AngleMap {
+        let mut pixels = [None; W_PIXELS];
+        pixels[0] = Some(Rgb::new(v, v, v));
+        let line = ScreenLine {
+            screen_idx: 0,
+            addr: 3,
+            pixels,
+        };
+        (0..angles)
+            .map(|angle| (angle, [vec![line], vec![], vec![]]))
+            .collect()
+    }
+
+    fn lit_count(angle_map: &AngleMap) -> usize {
+        angle_map
+            .values()
+            .filter(|lines| lines[0][0].pixels[0] == Some(Rgb::WHITE))
+            .count()
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let mut frame = gray_frame(200, 1);
+        ColorPipeline::default().apply(&mut frame);
+        assert_eq!(frame, gray_frame(200, 1));
+
+        let mut frame = gray_frame(200, 1);
+        ColorPipeline::new([1.0; 3], 0.5, 8, Dither::None).apply(&mut frame);
+        assert_eq!(frame[&0][0][0].pixels[0], Some(Rgb::new(100, 100, 100)));
+
+        let mut frame = gray_frame(100, 1);
+        ColorPipeline::new([2.2, 1.0, 1.0], 1.0, 3, Dither::None).apply(&mut frame);
+        // 100 -> 33 after gamma -> level 1 of 7
+        assert_eq!(frame[&0][0][0].pixels[0], Some(Rgb::new(36, 109, 109)));
+    }
+
+    #[test]
+    fn test_dither_keeps_average() {
+        for dither in [Dither::Ordered, Dither::ErrorDiffusion] {
+            let mut frame = gray_frame(64, 64);
+            ColorPipeline::new([1.0; 3], 1.0, 1, dither).apply(&mut frame);
+            let lit = lit_count(&frame);
+            assert!((14..=18).contains(&lit), "{dither:?} lit {lit}");
+        }
+        let mut frame = gray_frame(64, 64);
+        ColorPipeline::new([1.0; 3], 1.0, 1, Dither::None).apply(&mut frame);
+        assert_eq!(lit_count(&frame), 0);
+    }
+
+    #[test]
+    fn test_screen_calibration() {
+        let c = Rgb::new(200, 100, 10);
+        assert_eq!(ScreenCalibration::default().apply(c), c);
+        assert_eq!(
+            ScreenCalibration::with_gain(0.5).apply(c),
+            Rgb::new(100, 50, 5)
+        );
+        let warm = ScreenCalibration {
+            matrix: [[1., 0., 0.], [0., 0.9, 0.], [0.1, 0., 0.8]],
+            gain: 2.,
+        };
+        assert_eq!(warm.apply(c), Rgb::new(255, 180, 56));
+    }
+
+    #[test]
+    fn test_rgb24() {
+        let c = Rgb::from_rgb24(0x12_3456);
+        assert_eq!(c, Rgb::new(0x12, 0x34, 0x56));
+        assert_eq!(c.to_rgb24(), 0x12_3456);
+    }
+}
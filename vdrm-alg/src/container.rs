@@ -0,0 +1,388 @@
+//! Container file holding many frames for standalone playback.
+//!
+//! All integers are little-endian.
+//!
+//! | size             | field                                                |
+//! |------------------|------------------------------------------------------|
+//! | 4                | magic `b"VDRC"`                                      |
+//! | 2                | format version, currently [`CONTAINER_VERSION`]      |
+//! | 2                | reserved, zero                                       |
+//! | 4                | number of frames                                     |
+//! | 4                | number of clips                                      |
+//! | 4                | CRC-32 of the frame index and clip table             |
+//! | 4                | reserved, zero                                       |
+//! | 16 * frames      | frame index: offset `u64`, length `u32`, duration ms `u32` |
+//! | 12 * clips       | clip table: first frame `u32`, frame count `u32`, loops `u32` |
+//! | ...              | frames, each in the [`crate::frame`] format          |
+//!
+//! The clip table is the playlist, clips are played in order. A clip with
+//! zero loops repeats forever.
+
+use crate::frame::{crc32, Frame, FrameError};
+use std::fmt::Display;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub const CONTAINER_MAGIC: [u8; 4] = *b"VDRC";
+pub const CONTAINER_VERSION: u16 = 1;
+const HEADER_LEN: usize = 24;
+const INDEX_ENTRY_LEN: usize = 16;
+const CLIP_ENTRY_LEN: usize = 12;
+
+#[derive(Debug)]
+pub enum ContainerError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Crc {
+        expected: u32,
+        actual: u32,
+    },
+    NoFrame(usize),
+    BadClip(usize),
+    /// Index entry of the frame runs past the end of the stream.
+    BadEntry(usize),
+    /// The frame index and clip table do not fit in the stream.
+    BadIndex {
+        frames: u32,
+        clips: u32,
+    },
+    Frame(FrameError),
+}
+
+impl Display for ContainerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerError::Io(err) => write!(f, "io error: {err}"),
+            ContainerError::BadMagic => write!(f, "bad container magic"),
+            ContainerError::UnsupportedVersion(v) => {
+                write!(f, "unsupported container version {v}")
+            }
+            ContainerError::Crc { expected, actual } => write!(
+                f,
+                "index crc mismatch expected {expected:08x} actual {actual:08x}"
+            ),
+            ContainerError::NoFrame(idx) => write!(f, "frame {idx} out of range"),
+            ContainerError::BadClip(idx) => write!(f, "clip {idx} points past the last frame"),
+            ContainerError::BadEntry(idx) => write!(f, "frame {idx} runs past the stream end"),
+            ContainerError::BadIndex { frames, clips } => write!(
+                f,
+                "index of {frames} frames and {clips} clips exceeds the stream"
+            ),
+            ContainerError::Frame(err) => write!(f, "bad frame: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {}
+
+impl From<std::io::Error> for ContainerError {
+    fn from(err: std::io::Error) -> Self {
+        ContainerError::Io(err)
+    }
+}
+
+impl From<FrameError> for ContainerError {
+    fn from(err: FrameError) -> Self {
+        ContainerError::Frame(err)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameEntry {
+    pub offset: u64,
+    pub len: u32,
+    pub duration_ms: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Clip {
+    pub first_frame: u32,
+    pub frame_count: u32,
+    /// How many times the clip is played, 0 repeats it forever.
+    pub loops: u32,
+}
+
+impl Clip {
+    /// Frame indices of the clip, `None` if they overflow.
+    pub fn frames(&self) -> Option<std::ops::Range<usize>> {
+        let first = self.first_frame as usize;
+        Some(first..first.checked_add(self.frame_count as usize)?)
+    }
+}
+
+/// Collects clips in memory and writes the container in one go.
+#[derive(Default)]
+pub struct ContainerWriter {
+    frames: Vec<(Vec<u8>, u32)>,
+    clips: Vec<Clip>,
+}
+
+impl ContainerWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a clip of `(frame, duration in ms)` pairs, returns its index.
+    /// Nothing is added if a frame fails `Frame::to_bytes`.
+    pub fn add_clip<'a>(
+        &mut self,
+        frames: impl IntoIterator<Item = (&'a Frame, u32)>,
+        loops: u32,
+    ) -> Result<usize, FrameError> {
+        let first_frame = self.frames.len() as u32;
+        let frames: Vec<_> = frames
+            .into_iter()
+            .map(|(frame, duration_ms)| Ok((frame.to_bytes()?, duration_ms)))
+            .collect::<Result<_, FrameError>>()?;
+        self.frames.extend(frames);
+        self.clips.push(Clip {
+            first_frame,
+            frame_count: self.frames.len() as u32 - first_frame,
+            loops,
+        });
+        Ok(self.clips.len() - 1)
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        let mut tables = vec![];
+        let mut offset = (HEADER_LEN
+            + self.frames.len() * INDEX_ENTRY_LEN
+            + self.clips.len() * CLIP_ENTRY_LEN) as u64;
+        for (bytes, duration_ms) in &self.frames {
+            tables.extend(offset.to_le_bytes());
+            tables.extend((bytes.len() as u32).to_le_bytes());
+            tables.extend(duration_ms.to_le_bytes());
+            offset += bytes.len() as u64;
+        }
+        for clip in &self.clips {
+            tables.extend(clip.first_frame.to_le_bytes());
+            tables.extend(clip.frame_count.to_le_bytes());
+            tables.extend(clip.loops.to_le_bytes());
+        }
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend(CONTAINER_MAGIC);
+        header.extend(CONTAINER_VERSION.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend((self.frames.len() as u32).to_le_bytes());
+        header.extend((self.clips.len() as u32).to_le_bytes());
+        header.extend(crc32(&tables).to_le_bytes());
+        header.extend(0u32.to_le_bytes());
+        w.write_all(&header)?;
+        w.write_all(&tables)?;
+        for (bytes, _) in &self.frames {
+            w.write_all(bytes)?;
+        }
+        w.flush()
+    }
+}
+
+/// Reads the index on open and seeks to single frames on demand.
+pub struct ContainerReader<R> {
+    inner: R,
+    index: Vec<FrameEntry>,
+    clips: Vec<Clip>,
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+impl<R: Read + Seek> ContainerReader<R> {
+    pub fn open(mut inner: R) -> Result<Self, ContainerError> {
+        inner.seek(SeekFrom::Start(0))?;
+        let mut header = [0u8; HEADER_LEN];
+        inner.read_exact(&mut header)?;
+        if header[..4] != CONTAINER_MAGIC {
+            return Err(ContainerError::BadMagic);
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != CONTAINER_VERSION {
+            return Err(ContainerError::UnsupportedVersion(version));
+        }
+        let frame_count = u32_at(&header, 8) as usize;
+        let clip_count = u32_at(&header, 12) as usize;
+        let expected = u32_at(&header, 16);
+        let stream_len = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+        let tables_len = frame_count
+            .checked_mul(INDEX_ENTRY_LEN)
+            .zip(clip_count.checked_mul(CLIP_ENTRY_LEN))
+            .and_then(|(index, clips)| index.checked_add(clips))
+            .filter(|&len| len as u64 <= stream_len - HEADER_LEN as u64)
+            .ok_or(ContainerError::BadIndex {
+                frames: frame_count as u32,
+                clips: clip_count as u32,
+            })?;
+        let mut tables = vec![0u8; tables_len];
+        inner.read_exact(&mut tables)?;
+        let actual = crc32(&tables);
+        if expected != actual {
+            return Err(ContainerError::Crc { expected, actual });
+        }
+        let (index, clips) = tables.split_at(frame_count * INDEX_ENTRY_LEN);
+        let index: Vec<_> = index
+            .chunks_exact(INDEX_ENTRY_LEN)
+            .map(|e| FrameEntry {
+                offset: u64::from_le_bytes(e[..8].try_into().unwrap()),
+                len: u32_at(e, 8),
+                duration_ms: u32_at(e, 12),
+            })
+            .collect();
+        if let Some(idx) = index.iter().position(|e| {
+            e.offset
+                .checked_add(e.len as u64)
+                .is_none_or(|end| end > stream_len)
+        }) {
+            return Err(ContainerError::BadEntry(idx));
+        }
+        let clips: Vec<_> = clips
+            .chunks_exact(CLIP_ENTRY_LEN)
+            .map(|e| Clip {
+                first_frame: u32_at(e, 0),
+                frame_count: u32_at(e, 4),
+                loops: u32_at(e, 8),
+            })
+            .collect();
+        if let Some(idx) = clips
+            .iter()
+            .position(|c| c.frames().is_none_or(|frames| frames.end > index.len()))
+        {
+            return Err(ContainerError::BadClip(idx));
+        }
+        Ok(Self {
+            inner,
+            index,
+            clips,
+        })
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn entry(&self, idx: usize) -> Option<&FrameEntry> {
+        self.index.get(idx)
+    }
+
+    pub fn clips(&self) -> &[Clip] {
+        &self.clips
+    }
+
+    /// Seek to and parse a single frame.
+    pub fn frame(&mut self, idx: usize) -> Result<Frame, ContainerError> {
+        let entry = *self.index.get(idx).ok_or(ContainerError::NoFrame(idx))?;
+        self.inner.seek(SeekFrom::Start(entry.offset))?;
+        let mut buf = vec![0u8; entry.len as usize];
+        self.inner.read_exact(&mut buf)?;
+        Ok(Frame::from_bytes(&buf)?)
+    }
+
+    /// Frame indices in playback order, endless if a clip loops forever.
+    /// Clips without frames are skipped.
+    pub fn play_order(&self) -> impl Iterator<Item = usize> + '_ {
+        self.clips
+            .iter()
+            .filter(|clip| clip.frame_count > 0)
+            .flat_map(|clip| {
+                let loops = match clip.loops {
+                    0 => usize::MAX,
+                    n => n as usize,
+                };
+                // checked on open
+                let frames = clip.frames().unwrap();
+                std::iter::repeat_n(frames, loops).flatten()
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::AngleImage;
+    use std::io::Cursor;
+
+    fn frame(angle: u32) -> Frame {
+        let mut img = AngleImage::new(angle, 1);
+        img.coloum[0] = [1, 2, 3, angle as u8];
+        Frame {
+            geometry_hash: 42,
+            images: vec![img],
+        }
+    }
+
+    #[test]
+    fn test_container_seek() {
+        let frames: Vec<_> = (0..5).map(frame).collect();
+        let mut writer = ContainerWriter::new();
+        assert_eq!(
+            writer.add_clip(frames[..2].iter().map(|f| (f, 40)), 2),
+            Ok(0)
+        );
+        // an empty endless clip must not stall playback
+        assert_eq!(writer.add_clip([], 0), Ok(1));
+        let mut bad = frame(9);
+        bad.images[0].screen_idx = crate::NUM_SCREENS;
+        let bad_clip = [(&frames[2], 20), (&bad, 20)];
+        assert!(writer.add_clip(bad_clip, 0).is_err());
+        assert_eq!(
+            writer.add_clip(frames[2..].iter().map(|f| (f, 20)), 0),
+            Ok(2)
+        );
+        let mut buf = vec![];
+        writer.write(&mut buf).unwrap();
+
+        let mut reader = ContainerReader::open(Cursor::new(buf.clone())).unwrap();
+        assert_eq!(reader.frame_count(), 5);
+        assert_eq!(reader.clips()[2].frames(), Some(2..5));
+        assert_eq!(reader.entry(3).unwrap().duration_ms, 20);
+        assert_eq!(reader.frame(3).unwrap(), frames[3]);
+        assert_eq!(reader.frame(0).unwrap(), frames[0]);
+        assert!(matches!(reader.frame(5), Err(ContainerError::NoFrame(5))));
+        let order: Vec<_> = reader.play_order().take(9).collect();
+        assert_eq!(order, [0, 1, 0, 1, 2, 3, 4, 2, 3]);
+
+        let mut huge = buf.clone();
+        huge[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            ContainerReader::open(Cursor::new(huge)),
+            Err(ContainerError::BadIndex {
+                frames: u32::MAX,
+                clips: 3
+            })
+        ));
+
+        // corrupt tables with a valid crc
+        let tables_end = HEADER_LEN + 5 * INDEX_ENTRY_LEN + 3 * CLIP_ENTRY_LEN;
+        let with_crc = |mut buf: Vec<u8>| {
+            let crc = crc32(&buf[HEADER_LEN..tables_end]);
+            buf[16..20].copy_from_slice(&crc.to_le_bytes());
+            buf
+        };
+        let mut long = buf.clone();
+        let clip = HEADER_LEN + 5 * INDEX_ENTRY_LEN + 2 * CLIP_ENTRY_LEN;
+        long[clip + 4..clip + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            ContainerReader::open(Cursor::new(with_crc(long))),
+            Err(ContainerError::BadClip(2))
+        ));
+        let entry = HEADER_LEN + 4 * INDEX_ENTRY_LEN;
+        let mut past_end = buf.clone();
+        past_end[entry + 8..entry + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            ContainerReader::open(Cursor::new(with_crc(past_end))),
+            Err(ContainerError::BadEntry(4))
+        ));
+        let mut wrapping = buf.clone();
+        wrapping[entry..entry + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            ContainerReader::open(Cursor::new(with_crc(wrapping))),
+            Err(ContainerError::BadEntry(4))
+        ));
+
+        buf[HEADER_LEN] ^= 1;
+        assert!(matches!(
+            ContainerReader::open(Cursor::new(buf)),
+            Err(ContainerError::Crc { .. })
+        ));
+    }
+}
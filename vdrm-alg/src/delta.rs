@@ -0,0 +1,480 @@
+//! Changes between consecutive `AngleMap`s, so the serial link only carries
+//! what a new frame changes.
+//!
+//! [`AngleMapPatch::to_bytes`] is the transmission format. All integers are
+//! little-endian.
+//!
+//! | size | field                                        |
+//! |------|----------------------------------------------|
+//! | 4    | number of screen patches                     |
+//! | ...  | screen patches                               |
+//! | 4    | CRC-32 (IEEE) of every preceding byte        |
+//!
+//! A screen patch is the angle (`u32`), the screen index (`u8`), the number
+//! of added, removed and changed lines (`u16` each) and then those lines:
+//! - added: the address (`u32`), the mask as `MASK_WORDS` `u64`s, the run
+//!   count (`u16`) and the runs, each a length (`u16`) and `[r, g, b]`, as in
+//!   [`crate::sparse`],
+//! - removed: the address (`u32`),
+//! - changed: the address (`u32`), the pixel count (`u16`) and the pixels,
+//!   each an index (`u16`), `1` and `[r, g, b]` if lit or `0` if dark.
+//!
+//! Screens without changes are not written.
+
+use crate::frame::crc32;
+use crate::sparse::{compress, decompress, ColorRun, MASK_WORDS};
+use crate::{AngleMap, PixelColor, ScreenLine, NUM_SCREENS, W_PIXELS};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+const PATCH_HEADER_LEN: usize = 11;
+
+/// Pixels of one line that differ between two frames, as `(pixel index, new value)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineDiff {
+    pub addr: u32,
+    pub pixels: Vec<(u32, Option<PixelColor>)>,
+}
+
+/// Line level changes of one screen at one angle.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScreenPatch {
+    pub added: Vec<ScreenLine>,
+    pub removed: Vec<u32>,
+    pub changed: Vec<LineDiff>,
+}
+
+impl ScreenPatch {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Difference between two consecutive `AngleMap`s.
+///
+/// Only angles with at least one changed screen are stored, so a static scene
+/// produces an empty patch.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AngleMapPatch {
+    pub angles: BTreeMap<u32, [ScreenPatch; NUM_SCREENS]>,
+}
+
+impl AngleMapPatch {
+    pub fn is_empty(&self) -> bool {
+        self.angles.is_empty()
+    }
+
+    /// Number of added, removed and changed lines over all angles and screens.
+    pub fn num_lines(&self) -> usize {
+        self.angles
+            .values()
+            .flatten()
+            .map(|p| p.added.len() + p.removed.len() + p.changed.len())
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeltaError {
+    Truncated,
+    BadScreen(u8),
+    /// Runs of the added line at `offset` do not match its mask.
+    BadRuns(usize),
+    /// Changed pixel index past the end of the line.
+    BadPixel(u16),
+    Crc {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl Display for DeltaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeltaError::Truncated => write!(f, "patch truncated"),
+            DeltaError::BadScreen(idx) => write!(f, "screen index {idx} out of range"),
+            DeltaError::BadRuns(offset) => {
+                write!(f, "runs of the line at {offset} do not match its mask")
+            }
+            DeltaError::BadPixel(idx) => write!(f, "pixel index {idx} out of range"),
+            DeltaError::Crc { expected, actual } => {
+                write!(
+                    f,
+                    "crc mismatch expected {expected:08x} actual {actual:08x}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+/// Bounds checked little-endian reads of a patch body.
+struct Reader<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], DeltaError> {
+        let bytes = self
+            .buf
+            .get(self.offset..self.offset + N)
+            .ok_or(DeltaError::Truncated)?;
+        self.offset += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, DeltaError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DeltaError> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, DeltaError> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn color(&mut self) -> Result<PixelColor, DeltaError> {
+        let [r, g, b] = self.bytes()?;
+        Ok(PixelColor::new(r, g, b))
+    }
+}
+
+impl AngleMapPatch {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0; 4];
+        let mut count = 0u32;
+        for (angle, patches) in &self.angles {
+            for (screen_idx, patch) in patches.iter().enumerate() {
+                if patch.is_empty() {
+                    continue;
+                }
+                count += 1;
+                buf.extend(angle.to_le_bytes());
+                buf.push(screen_idx as u8);
+                for len in [patch.added.len(), patch.removed.len(), patch.changed.len()] {
+                    buf.extend((len as u16).to_le_bytes());
+                }
+                for line in &patch.added {
+                    let (mask, runs) = compress(&line.pixels);
+                    buf.extend(line.addr.to_le_bytes());
+                    for word in mask {
+                        buf.extend(word.to_le_bytes());
+                    }
+                    buf.extend((runs.len() as u16).to_le_bytes());
+                    for run in runs {
+                        buf.extend(run.len.to_le_bytes());
+                        buf.extend(run.color.channels());
+                    }
+                }
+                for addr in &patch.removed {
+                    buf.extend(addr.to_le_bytes());
+                }
+                for line_diff in &patch.changed {
+                    buf.extend(line_diff.addr.to_le_bytes());
+                    buf.extend((line_diff.pixels.len() as u16).to_le_bytes());
+                    for &(idx, pixel) in &line_diff.pixels {
+                        buf.extend((idx as u16).to_le_bytes());
+                        match pixel {
+                            Some(color) => {
+                                buf.push(1);
+                                buf.extend(color.channels());
+                            }
+                            None => buf.push(0),
+                        }
+                    }
+                }
+            }
+        }
+        buf[..4].copy_from_slice(&count.to_le_bytes());
+        let crc = crc32(&buf);
+        buf.extend(crc.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, DeltaError> {
+        if buf.len() < 8 {
+            return Err(DeltaError::Truncated);
+        }
+        let (body, crc) = buf.split_at(buf.len() - 4);
+        let expected = u32::from_le_bytes(crc.try_into().unwrap());
+        let actual = crc32(body);
+        if expected != actual {
+            return Err(DeltaError::Crc { expected, actual });
+        }
+        let mut reader = Reader {
+            buf: body,
+            offset: 0,
+        };
+        let count = reader.u32()?;
+        let mut angles: BTreeMap<u32, [ScreenPatch; NUM_SCREENS]> = BTreeMap::new();
+        for _ in 0..count {
+            if body.len() < reader.offset + PATCH_HEADER_LEN {
+                return Err(DeltaError::Truncated);
+            }
+            let angle = reader.u32()?;
+            let screen_idx = reader.u8()?;
+            if screen_idx as usize >= NUM_SCREENS {
+                return Err(DeltaError::BadScreen(screen_idx));
+            }
+            let [added, removed, changed] = [reader.u16()?, reader.u16()?, reader.u16()?];
+            let mut patch = ScreenPatch::default();
+            for _ in 0..added {
+                let offset = reader.offset;
+                let addr = reader.u32()?;
+                let mut mask = [0; MASK_WORDS];
+                for word in &mut mask {
+                    *word = reader.bytes().map(u64::from_le_bytes)?;
+                }
+                let runs = (0..reader.u16()?)
+                    .map(|_| {
+                        Ok(ColorRun {
+                            len: reader.u16()?,
+                            color: reader.color()?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, DeltaError>>()?;
+                let pixels = decompress(&mask, &runs).ok_or(DeltaError::BadRuns(offset))?;
+                patch.added.push(ScreenLine {
+                    screen_idx: screen_idx as usize,
+                    addr,
+                    pixels,
+                });
+            }
+            for _ in 0..removed {
+                patch.removed.push(reader.u32()?);
+            }
+            for _ in 0..changed {
+                let addr = reader.u32()?;
+                let pixels = (0..reader.u16()?)
+                    .map(|_| {
+                        let idx = reader.u16()?;
+                        if idx as usize >= W_PIXELS {
+                            return Err(DeltaError::BadPixel(idx));
+                        }
+                        let pixel = match reader.u8()? {
+                            0 => None,
+                            _ => Some(reader.color()?),
+                        };
+                        Ok((idx as u32, pixel))
+                    })
+                    .collect::<Result<_, _>>()?;
+                patch.changed.push(LineDiff { addr, pixels });
+            }
+            angles.entry(angle).or_default()[screen_idx as usize] = patch;
+        }
+        if reader.offset != body.len() {
+            return Err(DeltaError::Truncated);
+        }
+        Ok(Self { angles })
+    }
+}
+
+fn diff_lines(old: &[ScreenLine], new: &[ScreenLine]) -> ScreenPatch {
+    let old: BTreeMap<u32, &ScreenLine> = old.iter().map(|l| (l.addr, l)).collect();
+    let new: BTreeMap<u32, &ScreenLine> = new.iter().map(|l| (l.addr, l)).collect();
+    let mut patch = ScreenPatch::default();
+    for (addr, line) in &new {
+        let Some(old_line) = old.get(addr) else {
+            patch.added.push(**line);
+            continue;
+        };
+        let pixels: Vec<_> = old_line
+            .pixels
+            .iter()
+            .zip(&line.pixels)
+            .enumerate()
+            .filter(|(_, (o, n))| o != n)
+            .map(|(idx, (_, n))| (idx as u32, *n))
+            .collect();
+        if !pixels.is_empty() {
+            patch.changed.push(LineDiff {
+                addr: *addr,
+                pixels,
+            });
+        }
+    }
+    patch
+        .removed
+        .extend(old.keys().filter(|addr| !new.contains_key(addr)));
+    patch
+}
+
+/// Compute the patch that turns `old` into `new`.
+///
+/// An angle missing from one of the maps is treated as having no lines.
+pub fn diff(old: &AngleMap, new: &AngleMap) -> AngleMapPatch {
+    let empty: [Vec<ScreenLine>; NUM_SCREENS] = Default::default();
+    let mut angles = BTreeMap::new();
+    for angle in old.keys().chain(new.keys()) {
+        if angles.contains_key(angle) {
+            continue;
+        }
+        let old_lines = old.get(angle).unwrap_or(&empty);
+        let new_lines = new.get(angle).unwrap_or(&empty);
+        let patches: [ScreenPatch; NUM_SCREENS] =
+            std::array::from_fn(|idx| diff_lines(&old_lines[idx], &new_lines[idx]));
+        if patches.iter().all(ScreenPatch::is_empty) {
+            continue;
+        }
+        angles.insert(*angle, patches);
+    }
+    AngleMapPatch { angles }
+}
+
+/// Apply a patch produced by [`diff`] in place.
+///
+/// Lines stay sorted by address and angles left without any line are removed,
+/// so `apply(&mut old, &diff(&old, &new))` yields `new` for maps produced by
+/// `Codec::encode`.
+pub fn apply(angle_map: &mut AngleMap, patch: &AngleMapPatch) {
+    for (angle, patches) in &patch.angles {
+        let lines_arr = angle_map.entry(*angle).or_default();
+        for (lines, patch) in lines_arr.iter_mut().zip(patches) {
+            lines.retain(|l| !patch.removed.contains(&l.addr));
+            for line_diff in &patch.changed {
+                let Some(line) = lines.iter_mut().find(|l| l.addr == line_diff.addr) else {
+                    log::warn!("patch changes missing line {}", line_diff.addr);
+                    continue;
+                };
+                for &(idx, pixel) in &line_diff.pixels {
+                    if (idx as usize) < W_PIXELS {
+                        line.pixels[idx as usize] = pixel;
+                    }
+                }
+            }
+            for line in &patch.added {
+                match lines.binary_search_by_key(&line.addr, |l| l.addr) {
+                    Ok(idx) => lines[idx] = *line,
+                    Err(idx) => lines.insert(idx, *line),
+                }
+            }
+        }
+        if lines_arr.iter().all(Vec::is_empty) {
+            angle_map.remove(angle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line(screen_idx: usize, addr: u32, lit: &[(usize, u32)]) -> ScreenLine {
+        let mut pixels = [None; W_PIXELS];
+        for &(idx, color) in lit {
+            pixels[idx] = Some(PixelColor::from_rgb24(color));
+        }
+        ScreenLine {
+            screen_idx,
+            addr,
+            pixels,
+        }
+    }
+
+    #[test]
+    fn test_diff_apply() {
+        let mut old = AngleMap::new();
+        old.insert(
+            1,
+            [
+                vec![line(0, 3, &[(0, 1)]), line(0, 5, &[(2, 7)])],
+                vec![],
+                vec![line(2, 1, &[(4, 4)])],
+            ],
+        );
+        old.insert(2, [vec![line(0, 9, &[(1, 1)])], vec![], vec![]]);
+        let mut new = AngleMap::new();
+        new.insert(
+            1,
+            [
+                vec![line(0, 3, &[(0, 1), (1, 2)]), line(0, 4, &[(3, 3)])],
+                vec![],
+                vec![line(2, 1, &[(4, 4)])],
+            ],
+        );
+        new.insert(7, [vec![], vec![line(1, 0, &[(5, 5)])], vec![]]);
+
+        let patch = diff(&old, &new);
+        assert_eq!(patch.num_lines(), 5);
+        assert!(!patch.angles.contains_key(&0));
+        let mut applied = old.clone();
+        apply(&mut applied, &patch);
+        assert_eq!(applied, new);
+        assert!(diff(&new, &applied).is_empty());
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let mut old = AngleMap::new();
+        old.insert(
+            1,
+            [
+                vec![line(0, 3, &[(0, 1), (5, 0)])],
+                vec![line(1, 8, &[(9, 2)])],
+                vec![],
+            ],
+        );
+        let mut new = AngleMap::new();
+        new.insert(
+            1,
+            [
+                vec![line(0, 3, &[(1, 0xff00ff), (5, 0)])],
+                vec![],
+                vec![line(2, 140, &[(2, 3), (3, 3), (W_PIXELS - 1, 4)])],
+            ],
+        );
+        let patch = diff(&old, &new);
+        let bytes = patch.to_bytes();
+        // a line with one pixel turned dark and one lit, a removed line and an
+        // added line of two runs
+        let changed = PATCH_HEADER_LEN + 4 + 2 + (2 + 1) + (2 + 1 + 3);
+        let removed = PATCH_HEADER_LEN + 4;
+        let added = PATCH_HEADER_LEN + 4 + MASK_WORDS * 8 + 2 + 2 * 5;
+        assert_eq!(bytes.len(), 4 + changed + removed + added + 4);
+        let decoded = AngleMapPatch::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, patch);
+        let mut applied = old.clone();
+        apply(&mut applied, &decoded);
+        assert_eq!(applied, new);
+        assert_eq!(
+            AngleMapPatch::from_bytes(&AngleMapPatch::default().to_bytes()),
+            Ok(AngleMapPatch::default())
+        );
+
+        let mut corrupt = bytes.clone();
+        corrupt[5] ^= 1;
+        assert!(matches!(
+            AngleMapPatch::from_bytes(&corrupt),
+            Err(DeltaError::Crc { .. })
+        ));
+        let with_crc = |mut body: Vec<u8>| {
+            body.truncate(body.len() - 4);
+            let crc = crc32(&body);
+            body.extend(crc.to_le_bytes());
+            body
+        };
+        let mut bad_screen = bytes.clone();
+        bad_screen[8] = NUM_SCREENS as u8;
+        assert_eq!(
+            AngleMapPatch::from_bytes(&with_crc(bad_screen)),
+            Err(DeltaError::BadScreen(NUM_SCREENS as u8))
+        );
+        let mut more = bytes.clone();
+        more[0] += 1;
+        assert_eq!(
+            AngleMapPatch::from_bytes(&with_crc(more)),
+            Err(DeltaError::Truncated)
+        );
+        let mut bad_pixel = bytes.clone();
+        let pixel = 4 + PATCH_HEADER_LEN + 4 + 2;
+        bad_pixel[pixel..pixel + 2].copy_from_slice(&(W_PIXELS as u16).to_le_bytes());
+        assert_eq!(
+            AngleMapPatch::from_bytes(&with_crc(bad_pixel)),
+            Err(DeltaError::BadPixel(W_PIXELS as u16))
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use crate::{ScreenLineAddr, ScreenLinePixels};
+use std::collections::BTreeMap;
+
+/// Scan and addressing rules of the LED driver IC behind a panel.
+///
+/// Drivers that multiplex scan lines (MBI5264, ICN2053, FM6126 ...) light
+/// every line with the same `addr % scan_lines` in one scan slot, one per
+/// region of the column. Moving the pixels of a column to lines of the same
+/// scan group lets the panel show an angle with fewer scan slots.
+pub trait LedDriverProfile {
+    /// Number of scan groups, `None` if every line is addressed on its own.
+    fn scan_lines(&self) -> Option<u32>;
+    /// Pixels per region along a line. A panel narrower than one region has
+    /// a single region spanning the whole line.
+    fn region_len(&self) -> usize;
+    /// Regions in the order they pick the scan group of a pixel column,
+    /// centre region first by default.
+    fn region_order(&self, regions: usize) -> Vec<usize> {
+        let centre = regions / 2;
+        let mut order = vec![centre];
+        for d in 1..=centre + 1 {
+            if d <= centre {
+                order.push(centre - d);
+            }
+            if centre + d < regions {
+                order.push(centre + d);
+            }
+        }
+        order
+    }
+    /// Highest line address the panel accepts.
+    fn max_addr(&self) -> u32;
+}
+
+/// Plain shift registers, every line is latched independently.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PlainShiftRegister;
+
+impl LedDriverProfile for PlainShiftRegister {
+    fn scan_lines(&self) -> Option<u32> {
+        None
+    }
+    fn region_len(&self) -> usize {
+        crate::W_PIXELS
+    }
+    fn max_addr(&self) -> u32 {
+        u32::MAX
+    }
+}
+
+/// MBI5264 with 1/16 scan, three 64 pixel regions and 144 lines.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Mbi5264;
+
+impl LedDriverProfile for Mbi5264 {
+    fn scan_lines(&self) -> Option<u32> {
+        Some(16)
+    }
+    fn region_len(&self) -> usize {
+        64
+    }
+    fn max_addr(&self) -> u32 {
+        143
+    }
+}
+
+/// Whether `driver` moves pixels between lines of a `width` pixel panel,
+/// i.e. the panel has more than one region.
+pub(crate) fn groups_lines(driver: &dyn LedDriverProfile, width: usize) -> bool {
+    driver.scan_lines().is_some() && driver.region_len() < width
+}
+
+/// A lit pixel moved to another line of its scan group.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct ScanMove {
+    pub pixel: usize,
+    pub from: ScreenLineAddr,
+    pub to_addr: u32,
+}
+
+/// Find the moves putting every column on lines of one scan group, the group
+/// of the first non black pixel in region order. Pixels move to the nearest
+/// line of that group the driver can address, pixels without one stay.
+///
+/// `pixels_info` holds the lit pixel of every position along the panel, so
+/// the number of regions follows from its length.
+pub(crate) fn scan_line_moves(
+    driver: &dyn LedDriverProfile,
+    pixels_info: &[Option<([u8; 4], ScreenLineAddr)>],
+) -> Vec<ScanMove> {
+    let mut moves = vec![];
+    let Some(scan_lines) = driver.scan_lines() else {
+        return moves;
+    };
+    let scan_lines = scan_lines as i32;
+    let width = pixels_info.len();
+    let region_len = driver.region_len().clamp(1, width.max(1));
+    let region_order = driver.region_order(width.div_ceil(region_len));
+    for i in 0..region_len {
+        let regions: Vec<usize> = region_order
+            .iter()
+            .map(|r| i + r * region_len)
+            .filter(|&region| region < width)
+            .collect();
+        let mut non_empty_h: Option<u8> = None;
+        for &region in &regions {
+            let Some(pixel_info) = pixels_info[region] else {
+                continue;
+            };
+            if pixel_info.0[..3] == [0; 3] {
+                continue;
+            }
+            non_empty_h = Some(pixel_info.0[3]);
+            break;
+        }
+        let Some(non_empty_h) = non_empty_h else {
+            continue;
+        };
+        let non_empty_h_mod = non_empty_h as i32 % scan_lines;
+        for &region in &regions {
+            let Some((rgbh, line_addr)) = &pixels_info[region] else {
+                continue;
+            };
+            let h = rgbh[3] as i32;
+            let h_mod = h % scan_lines;
+            if h_mod == non_empty_h_mod {
+                continue;
+            }
+            // the nearest line of the group, or the one on the other side if
+            // the panel has no such line
+            let deta = non_empty_h_mod - h_mod;
+            let wrapped = if deta > 0 {
+                deta - scan_lines
+            } else {
+                deta + scan_lines
+            };
+            let candidates = match deta.abs() > scan_lines / 2 {
+                true => [wrapped, deta],
+                false => [deta, wrapped],
+            };
+            let Some(to_addr) = candidates
+                .into_iter()
+                .map(|deta| h + deta)
+                .find(|&to| to >= 0 && to as u32 <= driver.max_addr())
+            else {
+                continue;
+            };
+            moves.push(ScanMove {
+                pixel: region,
+                from: *line_addr,
+                to_addr: to_addr as u32,
+            });
+        }
+    }
+    moves
+}
+
+pub(crate) fn group_scan_lines(
+    driver: &dyn LedDriverProfile,
+    addr_map: &mut BTreeMap<ScreenLineAddr, ScreenLinePixels>,
+    pixels_info: &[Option<([u8; 4], ScreenLineAddr)>],
+) {
+    for m in scan_line_moves(driver, pixels_info) {
+        let line_pixels = addr_map.get_mut(&m.from).unwrap();
+        let pixel = line_pixels.pixels[m.pixel].take().unwrap();
+        let mut line_addr = m.from;
+        line_addr.addr = m.to_addr;
+        let entry = addr_map.entry(line_addr).or_default();
+        entry.pixels[m.pixel] = Some(pixel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Codec;
+
+    fn pixel_info(h: u8) -> Option<([u8; 4], ScreenLineAddr)> {
+        let addr = ScreenLineAddr {
+            screen_idx: 0,
+            addr: h as u32,
+        };
+        Some(([0xff, 0, 0, h], addr))
+    }
+
+    #[test]
+    fn test_region_order() {
+        assert_eq!(Mbi5264.region_order(1), [0]);
+        assert_eq!(Mbi5264.region_order(2), [1, 0]);
+        assert_eq!(Mbi5264.region_order(3), [1, 0, 2]);
+        assert_eq!(Mbi5264.region_order(4), [2, 1, 3, 0]);
+    }
+
+    #[test]
+    fn test_scan_line_moves_192() {
+        let mut pixels_info = vec![None; 192];
+        pixels_info[5] = pixel_info(3);
+        pixels_info[64 + 5] = pixel_info(20);
+        pixels_info[128 + 5] = pixel_info(47);
+        pixels_info[128 + 6] = pixel_info(47);
+        let moves = scan_line_moves(&Mbi5264, &pixels_info);
+        // the centre region picks scan group 20 % 16 = 4
+        let to: Vec<_> = moves.iter().map(|m| (m.pixel, m.to_addr)).collect();
+        assert_eq!(to, [(5, 4), (128 + 5, 52)]);
+        assert!(scan_line_moves(&PlainShiftRegister, &pixels_info).is_empty());
+    }
+
+    /// 1/8 scan with four 16 pixel regions on a 64 pixel panel, lines up to
+    /// the given address.
+    struct Quad(u32);
+
+    impl LedDriverProfile for Quad {
+        fn scan_lines(&self) -> Option<u32> {
+            Some(8)
+        }
+        fn region_len(&self) -> usize {
+            16
+        }
+        fn max_addr(&self) -> u32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_scan_line_moves_64() {
+        // one MBI5264 region spans the panel, every column is a single pixel
+        let pixels_info: Vec<_> = (0..64).map(|i| pixel_info(i as u8 * 3 % 40)).collect();
+        assert!(scan_line_moves(&Mbi5264, &pixels_info).is_empty());
+        let codec = Codec::new();
+        let pyramid = crate::pyramid_surface();
+        assert_eq!(
+            codec.encode(&pyramid, &Mbi5264),
+            codec.encode(&pyramid, &PlainShiftRegister)
+        );
+
+        let mut pixels_info = vec![None; 64];
+        // column 5, region 2 picks group 20 % 8 = 4
+        pixels_info[32 + 5] = pixel_info(20);
+        pixels_info[16 + 5] = pixel_info(3);
+        pixels_info[48 + 5] = pixel_info(12);
+        pixels_info[5] = pixel_info(13);
+        // column 7, region 3 picks group 30 % 8 = 6, 9 wraps down to 6
+        pixels_info[48 + 7] = pixel_info(30);
+        pixels_info[7] = pixel_info(9);
+        let moves = scan_line_moves(&Quad(63), &pixels_info);
+        let to: Vec<_> = moves.iter().map(|m| (m.pixel, m.to_addr)).collect();
+        assert_eq!(to, [(16 + 5, 4), (5, 12), (7, 6)]);
+        assert!(moves
+            .iter()
+            .all(|m| m.from.addr == pixels_info[m.pixel].unwrap().1.addr));
+
+        // 61 lines, not a multiple of the 8 scan groups
+        let mut pixels_info = vec![None; 64];
+        // column 2, group 5 is out of range above 58, so down to 53
+        pixels_info[32 + 2] = pixel_info(13);
+        pixels_info[2] = pixel_info(58);
+        // column 3, group 6 is 4 lines up
+        pixels_info[32 + 3] = pixel_info(14);
+        pixels_info[3] = pixel_info(2);
+        let to: Vec<_> = scan_line_moves(&Quad(60), &pixels_info)
+            .iter()
+            .map(|m| (m.pixel, m.to_addr))
+            .collect();
+        assert_eq!(to, [(2, 53), (3, 6)]);
+        // on a five line panel column 3 has no line of group 6 either way
+        pixels_info[2] = None;
+        assert!(scan_line_moves(&Quad(4), &pixels_info).is_empty());
+        assert!(!groups_lines(&Mbi5264, 64));
+        assert!(groups_lines(&Quad(63), 64));
+    }
+
+    #[test]
+    #[should_panic(expected = "scan line grouping")]
+    fn test_timing_with_grouping() {
+        let codec = Codec::with_geometry(crate::geometry::Geometry {
+            timing: crate::geometry::LineTiming {
+                rpm: 3600.0,
+                line_us: 3.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        codec.encode(&crate::pyramid_surface(), &Quad(63));
+    }
+}
@@ -0,0 +1,149 @@
+//! Dwell time brightness equalization.
+//!
+//! `Codec::encode` lights a voxel once on every screen that can reach it, so
+//! a voxel seen by three screens gets three light slots per turn and one
+//! seen by a single screen only one. A light slot lasts as long as its angle
+//! slot, which differs between slots of a non-uniform `Geometry::slots`.
+//! This optional pass dims the better lit voxels of a surface down to the
+//! dimmest one before encoding.
+
+use crate::assign::{assign, ScreenPolicy};
+use crate::{Codec, PixelSurface, NUM_SCREENS, TOTAL_ANGLES};
+
+/// Light of voxel `(x, y, z)` through the screens set in `mask`.
+fn mask_exposure(codec: &Codec, (x, y, z): (u32, u32, u32), mask: [bool; NUM_SCREENS]) -> f32 {
+    let geometry = codec.geometry();
+    let default_width = std::f32::consts::TAU / TOTAL_ANGLES as f32;
+    (0..NUM_SCREENS)
+        .filter(|&idx| mask[idx])
+        .filter_map(|idx| {
+            let z_info = codec.z_info(idx, x, y, z)?;
+            let width = geometry.slots.width(geometry.key_angle(z_info.angle));
+            Some(geometry.calibration[idx].gain * width / default_width)
+        })
+        .sum()
+}
+
+/// Relative light a voxel receives per turn under [`ScreenPolicy::All`]: the
+/// calibration gains of the screens that reach it, each times the width of
+/// the angle slot it is lit in, in default slot widths. 0 if no screen
+/// reaches the voxel.
+pub fn exposure(codec: &Codec, x: u32, y: u32, z: u32) -> f32 {
+    mask_exposure(codec, (x, y, z), [true; NUM_SCREENS])
+}
+
+/// Scale every voxel by `min exposure / exposure`, the minimum taken over the
+/// reachable voxels of `surface`, with screens assigned by `policy`.
+/// Unreachable voxels are kept as they are.
+pub fn equalize(codec: &Codec, surface: &PixelSurface, policy: ScreenPolicy) -> PixelSurface {
+    let exposures: Vec<f32> = assign(codec, surface, policy)
+        .into_iter()
+        .zip(surface)
+        .map(|(mask, &(x, y, (z, _)))| mask_exposure(codec, (x, y, z), mask))
+        .collect();
+    let min = exposures
+        .iter()
+        .copied()
+        .filter(|&e| e > 0.)
+        .fold(f32::INFINITY, f32::min);
+    surface
+        .iter()
+        .zip(exposures)
+        .map(|(&(x, y, (z, color)), e)| {
+            let color = if e > 0. { color.scale(min / e) } else { color };
+            (x, y, (z, color))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Rgb;
+    use crate::geometry::Geometry;
+    use crate::{H_PIXELS, W_PIXELS};
+
+    fn grid_surface() -> PixelSurface {
+        let mut surface = PixelSurface::new();
+        for x in (0..W_PIXELS as u32).step_by(3) {
+            for y in (0..W_PIXELS as u32).step_by(3) {
+                for z in (0..H_PIXELS as u32).step_by(3) {
+                    surface.push((x, y, (z, Rgb::WHITE)));
+                }
+            }
+        }
+        surface
+    }
+
+    fn surface_exposures(codec: &Codec, surface: &PixelSurface) -> Vec<f32> {
+        surface
+            .iter()
+            .map(|&(x, y, (z, _))| exposure(codec, x, y, z))
+            .collect()
+    }
+
+    /// Perceived brightness, color times exposure, is the same everywhere:
+    /// full white at the dimmest voxel.
+    fn assert_even(equalized: &PixelSurface, exposures: Vec<f32>) {
+        let min = exposures
+            .iter()
+            .copied()
+            .filter(|&e| e > 0.)
+            .fold(f32::INFINITY, f32::min);
+        for (&(_, _, (_, color)), e) in equalized.iter().zip(exposures) {
+            if e == 0. {
+                assert_eq!(color, Rgb::WHITE);
+                continue;
+            }
+            let light = color.r as f32 * e;
+            assert!(
+                (light - 255. * min).abs() <= e,
+                "exposure {e} color {color:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_equalize() {
+        let codec = Codec::new();
+        let surface = grid_surface();
+        let exposures = surface_exposures(&codec, &surface);
+        let max = exposures.iter().copied().fold(0., f32::max);
+        assert!(exposures.contains(&1.) && max > 1.);
+
+        let equalized = equalize(&codec, &surface, ScreenPolicy::All);
+        assert_even(&equalized, exposures);
+        // one screen per voxel, nothing to even out
+        assert_eq!(
+            equalize(&codec, &surface, ScreenPolicy::BestSingle),
+            surface
+        );
+    }
+
+    #[test]
+    fn test_exposure_slots() {
+        let geometry = Geometry::default();
+        let slots = geometry.facing_slots(TOTAL_ANGLES as u32, 4.0);
+        let codec = Codec::with_geometry(Geometry {
+            slots: slots.clone(),
+            ..geometry
+        });
+        let surface = grid_surface();
+        let exposures = surface_exposures(&codec, &surface);
+        let default_width = std::f32::consts::TAU / TOTAL_ANGLES as f32;
+        for (&(x, y, (z, _)), e) in surface.iter().zip(&exposures) {
+            let expected: f32 = (0..NUM_SCREENS)
+                .filter_map(|idx| codec.z_info(idx, x, y, z))
+                .map(|z_info| slots.width(codec.geometry().key_angle(z_info.angle)) / default_width)
+                .sum();
+            assert!((e - expected).abs() < 1e-5, "{e} {expected}");
+        }
+        // denser slots facing the screens light their voxels for less long
+        let uniform = surface_exposures(&Codec::new(), &surface);
+        assert!(exposures.iter().zip(&uniform).any(|(e, u)| e < u));
+        assert!(exposures.iter().any(|&e| e.fract() > 0.01));
+
+        let equalized = equalize(&codec, &surface, ScreenPolicy::All);
+        assert_even(&equalized, exposures);
+    }
+}
@@ -0,0 +1,275 @@
+//! Binary frame format shared by host tools and firmware.
+//!
+//! All integers are little-endian.
+//!
+//! | offset | size | field                                        |
+//! |--------|------|----------------------------------------------|
+//! | 0      | 4    | magic `b"VDRM"`                              |
+//! | 4      | 2    | format version, currently [`FRAME_VERSION`]  |
+//! | 6      | 2    | column length in pixels (`W_PIXELS`)         |
+//! | 8      | 4    | geometry hash, see [`geometry_hash`]         |
+//! | 12     | 4    | number of angle records                      |
+//! | 16     | ...  | angle records                                |
+//! | end-4  | 4    | CRC-32 (IEEE) of every preceding byte        |
+//!
+//! An angle record is the angle (`u32`), the screen index (`u8`) and then
+//! column length times `[r, g, b, h]`, `h` being the line address lit at that
+//! pixel. Black pixels are dark.
+
+use crate::{Screen, H_PIXELS, MIRROR_OFFSET, NUM_SCREENS, W_PIXELS};
+use std::fmt::Display;
+
+pub const FRAME_MAGIC: [u8; 4] = *b"VDRM";
+pub const FRAME_VERSION: u16 = 1;
+const HEADER_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 5;
+const CRC_LEN: usize = 4;
+
+pub type Rgbh = [u8; 4];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AngleImage {
+    pub angle: u32,
+    pub screen_idx: usize,
+    // rgbh
+    pub coloum: [Rgbh; W_PIXELS],
+}
+
+impl AngleImage {
+    pub const fn new(angle: u32, screen_idx: usize) -> Self {
+        Self {
+            angle,
+            screen_idx,
+            coloum: [[0; 4]; W_PIXELS],
+        }
+    }
+}
+
+impl Display for AngleImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for rgbh in self.coloum {
+            let h = rgbh[3];
+            write!(f, "{h:02}")?;
+        }
+        writeln!(f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u16),
+    ColumnLen(u16),
+    BadScreen(usize),
+    Crc { expected: u32, actual: u32 },
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Truncated => write!(f, "frame truncated"),
+            FrameError::BadMagic => write!(f, "bad frame magic"),
+            FrameError::UnsupportedVersion(v) => write!(f, "unsupported frame version {v}"),
+            FrameError::ColumnLen(len) => {
+                write!(f, "column length {len} does not match {W_PIXELS}")
+            }
+            FrameError::BadScreen(idx) => write!(f, "screen index {idx} out of range"),
+            FrameError::Crc { expected, actual } => {
+                write!(
+                    f,
+                    "crc mismatch expected {expected:08x} actual {actual:08x}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub geometry_hash: u32,
+    pub images: Vec<AngleImage>,
+}
+
+impl Frame {
+    /// Fails with `BadScreen` on an image of no screen, the screen index has
+    /// to fit the record.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, FrameError> {
+        let record_len = RECORD_HEADER_LEN + W_PIXELS * 4;
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.images.len() * record_len + CRC_LEN);
+        buf.extend(FRAME_MAGIC);
+        buf.extend(FRAME_VERSION.to_le_bytes());
+        buf.extend((W_PIXELS as u16).to_le_bytes());
+        buf.extend(self.geometry_hash.to_le_bytes());
+        buf.extend((self.images.len() as u32).to_le_bytes());
+        for img in &self.images {
+            if img.screen_idx >= NUM_SCREENS {
+                return Err(FrameError::BadScreen(img.screen_idx));
+            }
+            buf.extend(img.angle.to_le_bytes());
+            buf.push(img.screen_idx as u8);
+            buf.extend(img.coloum.iter().flatten());
+        }
+        let crc = crc32(&buf);
+        buf.extend(crc.to_le_bytes());
+        Ok(buf)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, FrameError> {
+        if buf.len() < HEADER_LEN + CRC_LEN {
+            return Err(FrameError::Truncated);
+        }
+        let (body, crc) = buf.split_at(buf.len() - CRC_LEN);
+        if body[..4] != FRAME_MAGIC {
+            return Err(FrameError::BadMagic);
+        }
+        let version = u16::from_le_bytes([body[4], body[5]]);
+        if version != FRAME_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let column_len = u16::from_le_bytes([body[6], body[7]]);
+        if column_len as usize != W_PIXELS {
+            return Err(FrameError::ColumnLen(column_len));
+        }
+        let geometry_hash = u32::from_le_bytes(body[8..12].try_into().unwrap());
+        let count = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+        let record_len = RECORD_HEADER_LEN + W_PIXELS * 4;
+        if count.checked_mul(record_len) != Some(body.len() - HEADER_LEN) {
+            return Err(FrameError::Truncated);
+        }
+        let expected = u32::from_le_bytes(crc.try_into().unwrap());
+        let actual = crc32(body);
+        if expected != actual {
+            return Err(FrameError::Crc { expected, actual });
+        }
+        let images = body[HEADER_LEN..]
+            .chunks_exact(record_len)
+            .map(|record| {
+                let angle = u32::from_le_bytes(record[..4].try_into().unwrap());
+                let screen_idx = record[4] as usize;
+                if screen_idx >= NUM_SCREENS {
+                    return Err(FrameError::BadScreen(screen_idx));
+                }
+                let mut img = AngleImage::new(angle, screen_idx);
+                for (rgbh, bytes) in img
+                    .coloum
+                    .iter_mut()
+                    .zip(record[RECORD_HEADER_LEN..].chunks_exact(4))
+                {
+                    *rgbh = bytes.try_into().unwrap();
+                }
+                Ok(img)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            geometry_hash,
+            images,
+        })
+    }
+}
+
+/// FNV-1a hash over the panel size, the `angles` slots per turn and screen
+/// placement, so a frame encoded for one build is not played on another.
+pub fn geometry_hash(screens: &[Screen], angles: u32) -> u32 {
+    let mut hash = FNV_OFFSET;
+    for v in [W_PIXELS as u32, H_PIXELS as u32, angles, NUM_SCREENS as u32] {
+        hash = fnv1a(hash, &v.to_le_bytes());
+    }
+    hash = fnv1a(hash, &MIRROR_OFFSET.to_le_bytes());
+    for screen in screens {
+        for (x, y, z) in screen.points {
+            for v in [x, y, z] {
+                hash = fnv1a(hash, &v.to_le_bytes());
+            }
+        }
+    }
+    hash
+}
+
+pub(crate) const FNV_OFFSET: u32 = 0x811c_9dc5;
+
+/// Continue an FNV-1a hash with `bytes`.
+pub(crate) fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+    for &b in bytes {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// CRC-32 with the IEEE polynomial, as used by zlib and most MCU CRC units.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::TOTAL_ANGLES;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        let mut img = AngleImage::new(130, 2);
+        img.coloum[3] = [1, 2, 3, 4];
+        let frame = Frame {
+            geometry_hash: geometry_hash(crate::screens(), TOTAL_ANGLES as u32),
+            images: vec![AngleImage::new(128, 0), img],
+        };
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + 2 * (5 + W_PIXELS * 4) + CRC_LEN);
+        assert_eq!(Frame::from_bytes(&bytes).as_ref(), Ok(&frame));
+
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_LEN + 2] ^= 1;
+        assert!(matches!(
+            Frame::from_bytes(&corrupt),
+            Err(FrameError::Crc { .. })
+        ));
+        assert_eq!(
+            Frame::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(FrameError::Truncated)
+        );
+        let mut huge = bytes.clone();
+        huge[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Frame::from_bytes(&huge), Err(FrameError::Truncated));
+
+        let mut bad = frame.clone();
+        bad.images[1].screen_idx = 256;
+        assert_eq!(bad.to_bytes(), Err(FrameError::BadScreen(256)));
+        bad.images[1].screen_idx = NUM_SCREENS;
+        assert_eq!(bad.to_bytes(), Err(FrameError::BadScreen(NUM_SCREENS)));
+        let mut bad_record = bytes.clone();
+        bad_record[HEADER_LEN + 4] = NUM_SCREENS as u8;
+        let body = bad_record.len() - CRC_LEN;
+        let crc = crc32(&bad_record[..body]);
+        bad_record[body..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(
+            Frame::from_bytes(&bad_record),
+            Err(FrameError::BadScreen(NUM_SCREENS))
+        );
+    }
+
+    #[test]
+    fn test_geometry_hash_angles() {
+        let screens = crate::screens();
+        assert_ne!(
+            geometry_hash(screens, TOTAL_ANGLES as u32),
+            geometry_hash(screens, TOTAL_ANGLES as u32 / 2)
+        );
+    }
+}
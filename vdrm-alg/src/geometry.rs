@@ -0,0 +1,819 @@
+use crate::color::ScreenCalibration;
+use crate::{frame, screens_with_rotate, Screen, NUM_FACETS, NUM_SCREENS, TOTAL_ANGLES, W_PIXELS};
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+/// Build error of one mirror facet, all zero for a perfect rotor.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FacetError {
+    /// Radians the facet normal leans out of the rotation plane, i.e. the
+    /// deviation from the nominal 45° facet.
+    pub tilt: f32,
+    /// Radians the facet normal is turned around the rotor axis.
+    pub azimuth: f32,
+    /// Deviation from `MIRROR_OFFSET`, the facet's distance to the axis.
+    pub offset: f32,
+}
+
+impl FacetError {
+    fn to_bytes(self) -> [u8; 12] {
+        let mut bytes = [0; 12];
+        bytes[..4].copy_from_slice(&self.tilt.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.azimuth.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.offset.to_le_bytes());
+        bytes
+    }
+}
+
+/// Order a panel scans its lines within one angle slot.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ScanOrder {
+    /// Line 0 first.
+    #[default]
+    Ascending,
+    /// Last line first.
+    Descending,
+    /// Lines with the same `addr % n` are lit together, group 0 first, as
+    /// multiplexing drivers do.
+    Grouped(u32),
+}
+
+impl ScanOrder {
+    /// Lines scanned before `addr`.
+    pub fn position(self, addr: u32) -> u32 {
+        match self {
+            Self::Ascending => addr,
+            Self::Descending => (W_PIXELS as u32 - 1).saturating_sub(addr),
+            Self::Grouped(n) => addr % n.max(1),
+        }
+    }
+}
+
+/// When a panel lights each line of an angle slot. The mirror keeps turning
+/// meanwhile, so a late line is reflected at a later angle than the slot's.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct LineTiming {
+    /// Rotor speed, 0 turns the compensation off.
+    pub rpm: f32,
+    /// Microseconds from the start of a slot to the first line.
+    pub latency_us: f32,
+    /// Microseconds per scanned line.
+    pub line_us: f32,
+    pub order: ScanOrder,
+}
+
+impl LineTiming {
+    pub fn is_zero(&self) -> bool {
+        self.rpm == 0.0 || (self.latency_us == 0.0 && self.line_us == 0.0)
+    }
+
+    /// Duration of one of `slots` angle slots, the mean for a slot table.
+    pub fn slot_us(&self, slots: &AngleSlots) -> f32 {
+        60e6 / (self.rpm * slots.len() as f32)
+    }
+
+    /// Microseconds from the start of a slot until panel line `addr` is lit.
+    pub fn line_delay_us(&self, addr: u32) -> f32 {
+        self.latency_us + self.order.position(addr) as f32 * self.line_us
+    }
+
+    /// Radians the rotor turns before panel line `addr` is lit.
+    pub fn line_angle(&self, addr: u32) -> f32 {
+        if self.is_zero() {
+            return 0.0;
+        }
+        self.line_delay_us(addr) * self.rpm * std::f32::consts::TAU / 60e6
+    }
+
+    fn to_bytes(self) -> [u8; 17] {
+        let mut bytes = [0; 17];
+        bytes[..4].copy_from_slice(&self.rpm.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.latency_us.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.line_us.to_le_bytes());
+        let (order, n) = match self.order {
+            ScanOrder::Ascending => (0, 0),
+            ScanOrder::Descending => (1, 0),
+            ScanOrder::Grouped(n) => (2, n),
+        };
+        bytes[12] = order;
+        bytes[13..].copy_from_slice(&n.to_le_bytes());
+        bytes
+    }
+}
+
+/// Facet reflecting at rotor angle `angle_f` in `[0, TAU)`.
+fn facet_at(angle_f: f32) -> usize {
+    let per_facet = std::f32::consts::TAU / NUM_FACETS as f32;
+    (angle_f / per_facet + 0.5) as usize % NUM_FACETS
+}
+
+/// Why [`AngleSlots::check`] rejected a slot layout.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlotsError {
+    Empty,
+    /// The angle of this slot is not within `[0, TAU)`.
+    OutOfRange(usize),
+    /// The angle of this slot is not above the one before.
+    NotAscending(usize),
+}
+
+impl Display for SlotsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlotsError::Empty => write!(f, "no angle slots"),
+            SlotsError::OutOfRange(idx) => write!(f, "angle slot {idx} out of one turn"),
+            SlotsError::NotAscending(idx) => write!(f, "angle slot {idx} not ascending"),
+        }
+    }
+}
+
+impl std::error::Error for SlotsError {}
+
+/// Rotor angles the codec renders, one per angle slot. The slot index is the
+/// codec angle, `Geometry::angle_key` turns it into an `AngleMap` key.
+///
+/// The layout must pass [`AngleSlots::check`], the methods panic on empty
+/// slots. `Codec::try_with_geometry` checks it.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AngleSlots {
+    /// Evenly spaced slots per turn, at least one.
+    Uniform(u32),
+    /// Radians of every slot, strictly ascending within `[0, TAU)`. Must not
+    /// be empty.
+    Table(Vec<f32>),
+}
+
+impl Default for AngleSlots {
+    fn default() -> Self {
+        Self::Uniform(TOTAL_ANGLES as u32)
+    }
+}
+
+impl AngleSlots {
+    /// Slots at the radians of `table`, see [`AngleSlots::check`].
+    pub fn table(table: Vec<f32>) -> Result<Self, SlotsError> {
+        let slots = Self::Table(table);
+        slots.check()?;
+        Ok(slots)
+    }
+
+    /// Reject empty layouts and tables not strictly ascending within one turn.
+    pub fn check(&self) -> Result<(), SlotsError> {
+        if self.is_empty() {
+            return Err(SlotsError::Empty);
+        }
+        let Self::Table(table) = self else {
+            return Ok(());
+        };
+        let turn = 0.0..std::f32::consts::TAU;
+        if let Some(idx) = table.iter().position(|a| !turn.contains(a)) {
+            return Err(SlotsError::OutOfRange(idx));
+        }
+        match table.windows(2).position(|w| w[1] <= w[0]) {
+            Some(idx) => Err(SlotsError::NotAscending(idx + 1)),
+            None => Ok(()),
+        }
+    }
+
+    /// `count` slots starting at 0 whose spacing is inverse to `density`, a
+    /// non negative weight of a rotor angle in radians.
+    pub fn weighted(count: u32, density: impl Fn(f32) -> f32) -> Self {
+        let count = count.max(1);
+        let samples = count as usize * 16;
+        let step = std::f32::consts::TAU / samples as f32;
+        let mut cumulative = vec![0f32];
+        for i in 0..samples {
+            let weight = density((i as f32 + 0.5) * step).max(0.0);
+            cumulative.push(cumulative[i] + weight);
+        }
+        let total = cumulative[samples];
+        if total <= 0.0 {
+            return Self::Uniform(count);
+        }
+        let mut table = Vec::with_capacity(count as usize);
+        let mut i = 0;
+        for slot in 0..count {
+            let target = total * slot as f32 / count as f32;
+            while cumulative[i + 1] <= target {
+                i += 1;
+            }
+            let fraction = (target - cumulative[i]) / (cumulative[i + 1] - cumulative[i]);
+            table.push((i as f32 + fraction) * step);
+        }
+        Self::Table(table)
+    }
+
+    pub fn len(&self) -> u32 {
+        match self {
+            Self::Uniform(count) => *count,
+            Self::Table(table) => table.len() as u32,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Rotor angle of `slot` in radians.
+    pub fn angle(&self, slot: u32) -> f32 {
+        match self {
+            Self::Uniform(count) => (slot as f32 * 360. / *count as f32).to_radians(),
+            Self::Table(table) => table[slot as usize % table.len()],
+        }
+    }
+
+    /// Radians from `slot` to the next slot.
+    pub fn width(&self, slot: u32) -> f32 {
+        match self {
+            Self::Uniform(count) => std::f32::consts::TAU / *count as f32,
+            Self::Table(table) => {
+                let idx = slot as usize % table.len();
+                let next = match table.get(idx + 1) {
+                    Some(&next) => next,
+                    None => table[0] + std::f32::consts::TAU,
+                };
+                next - table[idx]
+            }
+        }
+    }
+
+    /// Facet reflecting at `slot`, facets are centred on multiples of a
+    /// `NUM_FACETS`th turn.
+    pub fn facet(&self, slot: u32) -> usize {
+        let facets = NUM_FACETS as u64;
+        match self {
+            Self::Uniform(count) => {
+                let count = *count as u64;
+                ((slot as u64 * 2 * facets + count) / (2 * count)) as usize % NUM_FACETS
+            }
+            Self::Table(_) => facet_at(self.angle(slot)),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Uniform(count) => count.to_le_bytes().to_vec(),
+            Self::Table(table) => table.iter().flat_map(|a| a.to_le_bytes()).collect(),
+        }
+    }
+}
+
+/// Quarter turns of a panel, clockwise seen from the LED side.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Rotation {
+    #[default]
+    R0,
+    R90,
+    R180,
+    R270,
+}
+
+/// How a panel is mounted, mapping the codec's `(addr, pixel)` of a screen to
+/// the panel's own. Rotation comes first, then the flips, then the offsets.
+/// Panels are `W_PIXELS` square, positions moved off the panel are not lit.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PanelMount {
+    pub rotation: Rotation,
+    /// Reverse the pixel order along a line.
+    pub flip_pixels: bool,
+    /// Reverse the line order.
+    pub flip_addr: bool,
+    pub addr_offset: i32,
+    pub pixel_offset: i32,
+}
+
+impl PanelMount {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Panel `(addr, pixel)` of a codec position, `None` if it is off the panel.
+    pub fn to_panel(&self, addr: u32, pixel: u32) -> Option<(u32, u32)> {
+        let last = W_PIXELS as u32 - 1;
+        let (mut addr, mut pixel) = match self.rotation {
+            Rotation::R0 => (addr, pixel),
+            Rotation::R90 => (pixel, last.checked_sub(addr)?),
+            Rotation::R180 => (last.checked_sub(addr)?, last.checked_sub(pixel)?),
+            Rotation::R270 => (last.checked_sub(pixel)?, addr),
+        };
+        if self.flip_pixels {
+            pixel = last.checked_sub(pixel)?;
+        }
+        if self.flip_addr {
+            addr = last.checked_sub(addr)?;
+        }
+        let addr = shift(addr, self.addr_offset)?;
+        let pixel = shift(pixel, self.pixel_offset)?;
+        Some((addr, pixel))
+    }
+
+    /// Inverse of [`PanelMount::to_panel`].
+    pub fn from_panel(&self, addr: u32, pixel: u32) -> Option<(u32, u32)> {
+        let last = W_PIXELS as u32 - 1;
+        let mut addr = shift(addr, -self.addr_offset)?;
+        let mut pixel = shift(pixel, -self.pixel_offset)?;
+        if self.flip_addr {
+            addr = last.checked_sub(addr)?;
+        }
+        if self.flip_pixels {
+            pixel = last.checked_sub(pixel)?;
+        }
+        Some(match self.rotation {
+            Rotation::R0 => (addr, pixel),
+            Rotation::R90 => (last - pixel, addr),
+            Rotation::R180 => (last - addr, last - pixel),
+            Rotation::R270 => (pixel, last - addr),
+        })
+    }
+
+    fn to_bytes(self) -> [u8; 10] {
+        let mut bytes = [0; 10];
+        bytes[0] = self.rotation as u8;
+        bytes[1] = self.flip_pixels as u8 | (self.flip_addr as u8) << 1;
+        bytes[2..6].copy_from_slice(&self.addr_offset.to_le_bytes());
+        bytes[6..].copy_from_slice(&self.pixel_offset.to_le_bytes());
+        bytes
+    }
+}
+
+/// `v + offset` if it stays on the panel.
+fn shift(v: u32, offset: i32) -> Option<u32> {
+    let v = v as i64 + offset as i64;
+    (0..W_PIXELS as i64).contains(&v).then_some(v as u32)
+}
+
+/// Physical layout of a build, used by `Codec::with_geometry`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Geometry {
+    pub screens: [Screen; NUM_SCREENS],
+    /// Color correction per screen, applied to every pixel at encode time.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub calibration: [ScreenCalibration; NUM_SCREENS],
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mounts: [PanelMount; NUM_SCREENS],
+    /// Dead LEDs of each panel as `(addr, pixel)` after the mount. The codec
+    /// never lights them, see `Codec::dead_pixel_report`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub dead_pixels: [BTreeSet<(u32, u32)>; NUM_SCREENS],
+    /// Errors of each mirror facet, see `AngleSlots::facet` for which facet
+    /// an angle uses.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub facets: [FacetError; NUM_FACETS],
+    /// `AngleMap` key of codec angle 0, i.e. angle slots from the firmware's
+    /// index pulse to it.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub angle_phase: i32,
+    /// The rotor turns against the codec's angle direction, keys count down.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reverse_rotation: bool,
+    /// Rotor angles of the codec angles, a turn of `TOTAL_ANGLES` even
+    /// slots by default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub slots: AngleSlots,
+    /// Line timing the table compensates the rotor turn for. Drivers that
+    /// move pixels between lines of a scan group can't be used with it, see
+    /// `Codec::encode_with`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub timing: LineTiming,
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self {
+            screens: crate::screens().try_into().unwrap(),
+            calibration: Default::default(),
+            mounts: Default::default(),
+            dead_pixels: Default::default(),
+            facets: Default::default(),
+            angle_phase: 0,
+            reverse_rotation: false,
+            slots: Default::default(),
+            timing: Default::default(),
+        }
+    }
+}
+
+impl Geometry {
+    pub fn with_rotate(rad_rotate: f32, offset_middle_screen: Option<f32>) -> Self {
+        Self {
+            screens: screens_with_rotate(rad_rotate, offset_middle_screen),
+            calibration: Default::default(),
+            mounts: Default::default(),
+            dead_pixels: Default::default(),
+            facets: Default::default(),
+            angle_phase: 0,
+            reverse_rotation: false,
+            slots: Default::default(),
+            timing: Default::default(),
+        }
+    }
+
+    /// `AngleMap` key of a codec angle, see `angle_phase`.
+    pub fn angle_key(&self, angle: u32) -> u32 {
+        let total = self.slots.len() as i64;
+        let angle = match self.reverse_rotation {
+            true => -(angle as i64),
+            false => angle as i64,
+        };
+        (angle + self.angle_phase as i64).rem_euclid(total) as u32
+    }
+
+    /// Codec angle of an `AngleMap` key, inverse of [`Geometry::angle_key`].
+    pub fn key_angle(&self, key: u32) -> u32 {
+        let total = self.slots.len() as i64;
+        let angle = key as i64 - self.angle_phase as i64;
+        let angle = match self.reverse_rotation {
+            true => -angle,
+            false => angle,
+        };
+        angle.rem_euclid(total) as u32
+    }
+
+    /// `count` slots packed `boost` times denser at the rotor angles where
+    /// the mirror faces any screen, using the facet errors.
+    pub fn facing_slots(&self, count: u32, boost: f32) -> AngleSlots {
+        AngleSlots::weighted(count, |angle_f| {
+            let mat = crate::mirror_mat4_facet(angle_f, &self.facets[facet_at(angle_f)]);
+            match self.screens.iter().any(|s| crate::faces_screen(mat, s)) {
+                true => boost,
+                false => 1.0,
+            }
+        })
+    }
+
+    /// Mirror angle in radians while panel line `addr` of `slot` is lit. A
+    /// reversed rotor turns back to lower codec angles during the slot.
+    pub fn line_angle(&self, slot: u32, addr: u32) -> f32 {
+        let turn = self.timing.line_angle(addr);
+        match self.reverse_rotation {
+            true => self.slots.angle(slot) - turn,
+            false => self.slots.angle(slot) + turn,
+        }
+    }
+
+    /// Hash stored in frame headers, see [`frame::geometry_hash`], extended
+    /// with the panel mounts, dead pixels, facet errors, angle index, angle
+    /// slots and line timing if any is set.
+    /// Colors are not hashed, recalibrating keeps old frames valid.
+    pub fn hash(&self) -> u32 {
+        let mut hash = frame::geometry_hash(&self.screens, self.slots.len());
+        if self.mounts.iter().any(|m| !m.is_identity()) {
+            for mount in self.mounts {
+                hash = frame::fnv1a(hash, &mount.to_bytes());
+            }
+        }
+        if self.dead_pixels.iter().any(|d| !d.is_empty()) {
+            for (screen_idx, dead) in self.dead_pixels.iter().enumerate() {
+                for &(addr, pixel) in dead {
+                    hash = frame::fnv1a(hash, &[screen_idx as u8]);
+                    hash = frame::fnv1a(hash, &addr.to_le_bytes());
+                    hash = frame::fnv1a(hash, &pixel.to_le_bytes());
+                }
+            }
+        }
+        if self.facets.iter().any(|f| *f != FacetError::default()) {
+            for facet in self.facets {
+                hash = frame::fnv1a(hash, &facet.to_bytes());
+            }
+        }
+        if self.angle_phase != 0 || self.reverse_rotation {
+            hash = frame::fnv1a(hash, &self.angle_phase.to_le_bytes());
+            hash = frame::fnv1a(hash, &[self.reverse_rotation as u8]);
+        }
+        if self.slots != AngleSlots::default() {
+            hash = frame::fnv1a(hash, &self.slots.to_bytes());
+        }
+        if !self.timing.is_zero() {
+            hash = frame::fnv1a(hash, &self.timing.to_bytes());
+        }
+        hash
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Geometry {
+    /// Write the geometry as a JSON hardware profile, e.g. the geometry of a
+    /// `solver::Solution`.
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Read a hardware profile written by [`Geometry::save`].
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        Ok(serde_json::from_reader(file)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Rgb;
+    use crate::driver::{Mbi5264, PlainShiftRegister};
+    use crate::Codec;
+
+    /// Voxel a decoded view point falls into.
+    fn view_voxel((x, y, z): (f32, f32, f32)) -> Option<(u32, u32, u32)> {
+        let y = y - crate::V_IMG_CORD.y + crate::SCREEN_ZOOM;
+        crate::v3_2_pixel(x, y, crate::V_IMG_CORD.z - z)
+    }
+
+    #[test]
+    fn test_panel_mount() {
+        let last = W_PIXELS as u32 - 1;
+        let mount = PanelMount {
+            rotation: Rotation::R90,
+            ..Default::default()
+        };
+        assert_eq!(mount.to_panel(0, 0), Some((0, last)));
+        assert_eq!(mount.to_panel(0, 5), Some((5, last)));
+        let mount = PanelMount {
+            flip_addr: true,
+            pixel_offset: -2,
+            ..Default::default()
+        };
+        assert_eq!(mount.to_panel(3, 5), Some((last - 3, 3)));
+        assert_eq!(mount.to_panel(3, 1), None);
+
+        for rotation in [Rotation::R0, Rotation::R90, Rotation::R180, Rotation::R270] {
+            let mount = PanelMount {
+                rotation,
+                flip_pixels: true,
+                addr_offset: 3,
+                pixel_offset: -1,
+                ..Default::default()
+            };
+            for addr in 0..W_PIXELS as u32 {
+                for pixel in 0..W_PIXELS as u32 {
+                    if let Some((a, p)) = mount.to_panel(addr, pixel) {
+                        assert_eq!(mount.from_panel(a, p), Some((addr, pixel)));
+                    }
+                }
+            }
+        }
+        let mut geometry = Geometry::default();
+        let hash = geometry.hash();
+        geometry.mounts[2].flip_pixels = true;
+        assert_ne!(geometry.hash(), hash);
+    }
+
+    #[test]
+    fn test_mounted_encode_decode() {
+        let plain = Codec::new();
+        let mut geometry = Geometry::default();
+        geometry.mounts[0] = PanelMount {
+            rotation: Rotation::R180,
+            flip_pixels: true,
+            ..Default::default()
+        };
+        geometry.mounts[2].rotation = Rotation::R90;
+        let mounted = Codec::with_geometry(geometry);
+        for voxel in [(10, 20, 5), (32, 32, 20), (50, 12, 30)] {
+            let surface = vec![(voxel.0, voxel.1, (voxel.2, Rgb::WHITE))];
+            let plain_map = plain.encode(&surface, &PlainShiftRegister);
+            let mounted_map = mounted.encode(&surface, &PlainShiftRegister);
+            assert_ne!(plain_map, mounted_map);
+            let (mut plain_view, _) = plain.decode_all(plain_map);
+            let (mut mounted_view, _) = mounted.decode_all(mounted_map);
+            plain_view.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            mounted_view.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(plain_view, mounted_view);
+        }
+    }
+
+    #[test]
+    fn test_dead_pixels() {
+        let plain = Codec::new();
+        let voxel = (32, 32, 20);
+        let z_info = plain.z_info(1, voxel.0, voxel.1, voxel.2).unwrap();
+        let dead = (z_info.screen_pixel.addr, z_info.screen_pixel.pixel);
+        let mut geometry = Geometry::default();
+        geometry.dead_pixels[1].insert(dead);
+        geometry.dead_pixels[1].insert((0, 0));
+        let hash = geometry.hash();
+        let codec = Codec::with_geometry(geometry.clone());
+        let report = codec.dead_pixel_report();
+        let mut rerouted = 0;
+        for x in 0..W_PIXELS as u32 {
+            for y in 0..W_PIXELS as u32 {
+                for z in 0..crate::H_PIXELS as u32 {
+                    let entries = |codec: &Codec| {
+                        (0..NUM_SCREENS)
+                            .map(|screen_idx| {
+                                codec
+                                    .z_info(screen_idx, x, y, z)
+                                    .map(|z_info| (z_info.angle, z_info.screen_pixel))
+                            })
+                            .collect::<Vec<_>>()
+                    };
+                    let (before, after) = (entries(&plain), entries(&codec));
+                    if before != after && after.iter().any(Option::is_some) {
+                        rerouted += 1;
+                    }
+                }
+            }
+        }
+        assert!(report.masked > 0);
+        assert_eq!(report.rerouted, rerouted);
+        assert_eq!(report.rerouted, 99);
+        assert!(report.unreachable.is_empty());
+        assert_ne!(hash, Geometry::default().hash());
+
+        let mut surface = vec![];
+        for x in 0..W_PIXELS as u32 {
+            for y in 0..W_PIXELS as u32 {
+                surface.push((x, y, (20, Rgb::WHITE)));
+            }
+        }
+        let angle_map = codec.encode(&surface, &PlainShiftRegister);
+        for line in angle_map.values().flat_map(|lines| &lines[1]) {
+            for (pixel, color) in line.pixels.iter().enumerate() {
+                let lit = (line.addr, pixel as u32);
+                assert!(color.is_none() || !geometry.dead_pixels[1].contains(&lit));
+            }
+        }
+
+        // nothing left to light
+        let all: BTreeSet<_> = (0..W_PIXELS as u32)
+            .flat_map(|addr| (0..W_PIXELS as u32).map(move |pixel| (addr, pixel)))
+            .collect();
+        geometry.dead_pixels = [all.clone(), all.clone(), all];
+        let codec = Codec::with_geometry(geometry);
+        let report = codec.dead_pixel_report();
+        assert_eq!(report.rerouted, 0);
+        assert!(report.unreachable.contains(&voxel));
+        assert!(codec.encode(&surface, &PlainShiftRegister).is_empty());
+    }
+
+    #[test]
+    fn test_angle_index() {
+        let plain = Codec::new();
+        let geometry = Geometry {
+            angle_phase: -10,
+            reverse_rotation: true,
+            ..Default::default()
+        };
+        assert_eq!(geometry.angle_key(0), TOTAL_ANGLES as u32 - 10);
+        assert_eq!(geometry.angle_key(5), TOTAL_ANGLES as u32 - 15);
+        for angle in 0..TOTAL_ANGLES as u32 {
+            assert_eq!(geometry.key_angle(geometry.angle_key(angle)), angle);
+        }
+        let codec = Codec::with_geometry(geometry.clone());
+        let mut surface = vec![];
+        for x in (0..W_PIXELS as u32).step_by(3) {
+            for y in (0..W_PIXELS as u32).step_by(3) {
+                surface.push((x, y, (12, Rgb::WHITE)));
+            }
+        }
+        let plain_map = plain.encode(&surface, &PlainShiftRegister);
+        let keyed_map = codec.encode(&surface, &PlainShiftRegister);
+        let expected: crate::AngleMap = plain_map
+            .iter()
+            .map(|(&angle, lines)| (geometry.angle_key(angle), lines.clone()))
+            .collect();
+        assert_eq!(keyed_map, expected);
+        let sorted = |(mut view, _): (crate::FloatSurface, crate::FloatSurface)| {
+            view.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            view
+        };
+        assert_eq!(
+            sorted(plain.decode_all(plain_map)),
+            sorted(codec.decode_all(keyed_map))
+        );
+    }
+
+    #[test]
+    fn test_angle_slots() {
+        let slots = AngleSlots::default();
+        for angle in 0..TOTAL_ANGLES as u32 {
+            let per_facet = (TOTAL_ANGLES / NUM_FACETS) as u32;
+            let facet = ((angle + per_facet / 2) / per_facet) as usize % NUM_FACETS;
+            assert_eq!(slots.facet(angle), facet);
+            assert_eq!(slots.angle(angle), crate::angle_to_v(angle));
+        }
+
+        let geometry = Geometry::default();
+        let boosted = geometry.facing_slots(256, 4.0);
+        let AngleSlots::Table(table) = &boosted else {
+            panic!("expected a slot table");
+        };
+        assert_eq!(table.len(), 256);
+        assert_eq!(table[0], 0.0);
+        assert!(table.windows(2).all(|w| w[0] < w[1]));
+        assert!(*table.last().unwrap() < std::f32::consts::TAU);
+        let steps: Vec<f32> = table.windows(2).map(|w| w[1] - w[0]).collect();
+        let min = steps.iter().cloned().fold(f32::MAX, f32::min);
+        let max = steps.iter().cloned().fold(0.0, f32::max);
+        assert!(max / min > 3.5, "min {min} max {max}");
+        assert_eq!(boosted.check(), Ok(()));
+        assert_eq!(AngleSlots::table(table.clone()).as_ref(), Ok(&boosted));
+
+        assert_eq!(AngleSlots::Uniform(0).check(), Err(SlotsError::Empty));
+        assert_eq!(AngleSlots::table(vec![]), Err(SlotsError::Empty));
+        assert_eq!(
+            AngleSlots::table(vec![0.0, 2.0, 1.0]),
+            Err(SlotsError::NotAscending(2))
+        );
+        assert_eq!(
+            AngleSlots::table(vec![0.0, 7.0]),
+            Err(SlotsError::OutOfRange(1))
+        );
+        let empty = Geometry {
+            slots: AngleSlots::Uniform(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Codec::try_with_geometry(empty),
+            Err(SlotsError::Empty)
+        ));
+
+        let geometry = Geometry {
+            slots: boosted,
+            ..Default::default()
+        };
+        assert_ne!(geometry.hash(), Geometry::default().hash());
+        let codec = Codec::with_geometry(geometry);
+        let voxel = (32, 32, 20);
+        let surface = vec![(voxel.0, voxel.1, (voxel.2, Rgb::WHITE))];
+        let angle_map = codec.encode(&surface, &PlainShiftRegister);
+        assert!(angle_map.keys().all(|&angle| angle < 256));
+        let (view, _) = codec.decode_all(angle_map);
+        assert!(!view.is_empty());
+        for p in view {
+            let (x, y, z) = view_voxel(p).unwrap();
+            assert!(
+                x.abs_diff(voxel.0) <= 1 && y.abs_diff(voxel.1) <= 1 && z.abs_diff(voxel.2) <= 1
+            );
+        }
+    }
+
+    #[test]
+    fn test_line_timing() {
+        let timing = LineTiming {
+            rpm: 3600.0,
+            latency_us: 4.0,
+            line_us: 2.0,
+            order: ScanOrder::Grouped(16),
+        };
+        assert_eq!(timing.line_delay_us(0), 4.0);
+        assert_eq!(timing.line_delay_us(21), 4.0 + 5.0 * 2.0);
+        assert_eq!(ScanOrder::Descending.position(0), W_PIXELS as u32 - 1);
+        assert!((timing.slot_us(&AngleSlots::default()) - 32.552).abs() < 1e-3);
+        assert_eq!(LineTiming::default().line_angle(40), 0.0);
+
+        // last lines are lit about six slots late
+        let timing = LineTiming {
+            line_us: 3.0,
+            order: ScanOrder::Ascending,
+            ..timing
+        };
+        let voxel = (32, 20, 20);
+        let surface = vec![(voxel.0, voxel.1, (voxel.2, Rgb::WHITE))];
+        let max_error = |codec: &Codec, angle_map| {
+            let (view, _) = codec.decode_all(angle_map);
+            assert!(!view.is_empty());
+            view.into_iter()
+                .map(|p| {
+                    let (x, y, z) = view_voxel(p).unwrap();
+                    x.abs_diff(voxel.0)
+                        .max(y.abs_diff(voxel.1))
+                        .max(z.abs_diff(voxel.2))
+                })
+                .max()
+                .unwrap()
+        };
+        for reverse_rotation in [false, true] {
+            let naive = Codec::with_geometry(Geometry {
+                reverse_rotation,
+                ..Default::default()
+            });
+            let geometry = Geometry {
+                timing,
+                reverse_rotation,
+                ..Default::default()
+            };
+            let slot_angle = geometry.slots.angle(100);
+            let last_line = geometry.line_angle(100, W_PIXELS as u32 - 1);
+            assert_eq!(last_line < slot_angle, reverse_rotation);
+            let codec = Codec::with_geometry(geometry);
+            let naive_map = naive.encode(&surface, &PlainShiftRegister);
+            assert!(max_error(&codec, naive_map) > 1, "{reverse_rotation}");
+            let map = codec.encode(&surface, &PlainShiftRegister);
+            // a single MBI5264 region spans the panel, no line is moved
+            assert_eq!(codec.encode(&surface, &Mbi5264), map);
+            assert_eq!(max_error(&codec, map), 0, "{reverse_rotation}");
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! HUB75 output using binary code modulation.
+//!
+//! Every screen is one panel of the chain, screen 0 nearest to the
+//! controller. A `ScreenLine` address is the panel row and its pixel index the
+//! column. Rows `r` and `r + scan rows` are driven together on the upper
+//! (R1 G1 B1) and lower (R2 G2 B2) color pins.
+
+use crate::{AngleMap, ScreenLine, NUM_SCREENS};
+use std::collections::BTreeMap;
+
+pub const R1: u8 = 1 << 0;
+pub const G1: u8 = 1 << 1;
+pub const B1: u8 = 1 << 2;
+pub const R2: u8 = 1 << 3;
+pub const G2: u8 = 1 << 4;
+pub const B2: u8 = 1 << 5;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScanRate {
+    Scan8,
+    Scan16,
+    Scan32,
+}
+
+impl ScanRate {
+    /// Row addresses, i.e. half the panel height.
+    pub fn rows(self) -> usize {
+        match self {
+            ScanRate::Scan8 => 8,
+            ScanRate::Scan16 => 16,
+            ScanRate::Scan32 => 32,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Hub75Config {
+    /// Bits per color channel, 1 to 8.
+    pub color_depth: u8,
+    pub scan: ScanRate,
+    /// Panels per chain.
+    pub chain_length: usize,
+    pub panel_width: usize,
+    /// OE low time of the least significant bit plane in clock ticks, every
+    /// following plane doubles it.
+    pub base_oe_ticks: u32,
+}
+
+impl Default for Hub75Config {
+    fn default() -> Self {
+        Self {
+            color_depth: 6,
+            scan: ScanRate::Scan32,
+            chain_length: NUM_SCREENS,
+            panel_width: crate::W_PIXELS,
+            base_oe_ticks: 1,
+        }
+    }
+}
+
+/// One latch of the shift registers: row address, bit plane, OE time and the
+/// color pin bytes in clock order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hub75Row {
+    pub addr: u8,
+    pub plane: u8,
+    pub oe_ticks: u32,
+    /// One byte per clock, bit 0 to 5 are R1 G1 B1 R2 G2 B2. The first byte
+    /// ends up in the last column of the last panel.
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hub75Frame {
+    pub rows: Vec<Hub75Row>,
+}
+
+impl Hub75Frame {
+    /// Per row: address `u8`, plane `u8`, OE ticks `u32` little-endian and the
+    /// clock bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for row in &self.rows {
+            buf.push(row.addr);
+            buf.push(row.plane);
+            buf.extend(row.oe_ticks.to_le_bytes());
+            buf.extend(&row.data);
+        }
+        buf
+    }
+}
+
+/// Bit planes of one angle, row by row with all planes of a row in a row.
+pub fn bit_planes(config: &Hub75Config, lines_arr: &[Vec<ScreenLine>; NUM_SCREENS]) -> Hub75Frame {
+    let scan_rows = config.scan.rows();
+    let depth = config.color_depth.clamp(1, 8);
+    let width = config.chain_length * config.panel_width;
+    // rgb of every (row, column) of the chain, top half rows first
+    let mut pixels = vec![[0u8; 3]; scan_rows * 2 * width];
+    for (screen_idx, lines) in lines_arr.iter().enumerate() {
+        if screen_idx >= config.chain_length {
+            break;
+        }
+        for line in lines {
+            let row = line.addr as usize;
+            if row >= scan_rows * 2 {
+                continue;
+            }
+            for (col, pixel) in line.pixels.iter().take(config.panel_width).enumerate() {
+                let Some(color) = pixel else {
+                    continue;
+                };
+                let col = screen_idx * config.panel_width + col;
+                pixels[row * width + col] = color.channels();
+            }
+        }
+    }
+    let mut rows = vec![];
+    for addr in 0..scan_rows {
+        for plane in 0..depth {
+            let bit = 8 - depth + plane;
+            let data = (0..width)
+                .rev()
+                .map(|col| {
+                    let upper = pixels[addr * width + col];
+                    let lower = pixels[(addr + scan_rows) * width + col];
+                    let mut byte = 0;
+                    for (value, mask) in upper.iter().chain(&lower).zip([R1, G1, B1, R2, G2, B2]) {
+                        if value >> bit & 1 == 1 {
+                            byte |= mask;
+                        }
+                    }
+                    byte
+                })
+                .collect();
+            rows.push(Hub75Row {
+                addr: addr as u8,
+                plane,
+                oe_ticks: config.base_oe_ticks << plane,
+                data,
+            });
+        }
+    }
+    Hub75Frame { rows }
+}
+
+/// Bit planes of every angle of an encoded frame.
+pub fn angle_map_frames(config: &Hub75Config, angle_map: &AngleMap) -> BTreeMap<u32, Hub75Frame> {
+    angle_map
+        .iter()
+        .map(|(&angle, lines_arr)| (angle, bit_planes(config, lines_arr)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Rgb;
+    use crate::W_PIXELS;
+
+    #[test]
+    fn test_bit_planes() {
+        let config = Hub75Config {
+            color_depth: 2,
+            scan: ScanRate::Scan8,
+            chain_length: 2,
+            panel_width: 4,
+            base_oe_ticks: 3,
+        };
+        let mut pixels = [None; W_PIXELS];
+        // r = 0b11.., g = 0b01.., b = 0
+        pixels[1] = Some(Rgb::new(0xff, 0x40, 0));
+        let line = ScreenLine {
+            screen_idx: 1,
+            addr: 8 + 2,
+            pixels,
+        };
+        let frame = bit_planes(&config, &[vec![], vec![line], vec![]]);
+        assert_eq!(frame.rows.len(), 8 * 2);
+        let row = &frame.rows[2 * 2];
+        assert_eq!((row.addr, row.plane, row.oe_ticks), (2, 0, 3));
+        // screen 1 column 1 is chain column 5, clocked third of eight
+        assert_eq!(row.data, [0, 0, R2 | G2, 0, 0, 0, 0, 0]);
+        let row = &frame.rows[2 * 2 + 1];
+        assert_eq!((row.plane, row.oe_ticks), (1, 6));
+        assert_eq!(row.data[2], R2);
+        assert!(frame.rows[0].data.iter().all(|&b| b == 0));
+        assert_eq!(frame.to_bytes().len(), 16 * (6 + 8));
+    }
+}
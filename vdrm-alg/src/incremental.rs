@@ -0,0 +1,233 @@
+use crate::collision::CollisionStats;
+use crate::driver::LedDriverProfile;
+use crate::{
+    parse_addr_map, put_pixel, AngleMap, Codec, EncodeOptions, PixelColor, PixelSurface,
+    ScreenLineAddr, ScreenLinePixels, NUM_SCREENS,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+type Voxel = (u32, u32, u32);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VoxelOp {
+    /// Light a voxel, recoloring it if it is already lit.
+    Insert {
+        x: u32,
+        y: u32,
+        z: u32,
+        color: PixelColor,
+    },
+    Remove {
+        x: u32,
+        y: u32,
+        z: u32,
+    },
+    /// Change the color of a lit voxel, ignored if the voxel is dark.
+    Recolor {
+        x: u32,
+        y: u32,
+        z: u32,
+        color: PixelColor,
+    },
+}
+
+/// Stateful encoder keeping the current `AngleMap` up to date with voxel edits.
+///
+/// Every voxel only touches one line per screen, so an edit re-encodes the
+/// (angle, screen) buckets the voxel maps to instead of the whole surface.
+/// The result is the same as `Codec::encode_with` over
+/// [`IncrementalEncoder::surface`] with the same options, except that
+/// `screen_policy` is always [`crate::assign::ScreenPolicy::All`] and `power`
+/// is not applied: they depend on the whole surface and need a full encode.
+pub struct IncrementalEncoder<'a> {
+    codec: &'a Codec,
+    options: EncodeOptions<'a>,
+    voxels: BTreeMap<Voxel, PixelColor>,
+    buckets: BTreeMap<(u32, usize), BTreeSet<Voxel>>,
+    collisions: BTreeMap<(u32, usize), CollisionStats>,
+    angle_map: AngleMap,
+}
+
+impl<'a> IncrementalEncoder<'a> {
+    /// # Panics
+    /// See [`Codec::encode_with`].
+    pub fn new(codec: &'a Codec, driver: &'a dyn LedDriverProfile) -> Self {
+        Self::with_options(codec, EncodeOptions::new(driver))
+    }
+
+    /// # Panics
+    /// See [`Codec::encode_with`].
+    pub fn with_options(codec: &'a Codec, options: EncodeOptions<'a>) -> Self {
+        codec.check_driver(options.driver);
+        Self {
+            codec,
+            options,
+            voxels: BTreeMap::new(),
+            buckets: BTreeMap::new(),
+            collisions: BTreeMap::new(),
+            angle_map: AngleMap::new(),
+        }
+    }
+
+    pub fn angle_map(&self) -> &AngleMap {
+        &self.angle_map
+    }
+
+    /// Collisions of the current frame, as `Codec::encode_with` reports them.
+    pub fn collisions(&self) -> CollisionStats {
+        let mut total = CollisionStats::default();
+        for stats in self.collisions.values() {
+            total.collisions += stats.collisions;
+            total.dropped += stats.dropped;
+        }
+        total
+    }
+
+    /// Current voxels ordered by (x, y, z).
+    pub fn surface(&self) -> PixelSurface {
+        self.voxels
+            .iter()
+            .map(|(&(x, y, z), &color)| (x, y, (z, color)))
+            .collect()
+    }
+
+    pub fn insert(&mut self, x: u32, y: u32, z: u32, color: PixelColor) {
+        self.apply([VoxelOp::Insert { x, y, z, color }]);
+    }
+
+    pub fn remove(&mut self, x: u32, y: u32, z: u32) {
+        self.apply([VoxelOp::Remove { x, y, z }]);
+    }
+
+    pub fn recolor(&mut self, x: u32, y: u32, z: u32, color: PixelColor) {
+        self.apply([VoxelOp::Recolor { x, y, z, color }]);
+    }
+
+    /// Apply a batch of edits, re-encoding each affected bucket once.
+    /// Returns the angles whose lines were re-encoded.
+    pub fn apply(&mut self, ops: impl IntoIterator<Item = VoxelOp>) -> BTreeSet<u32> {
+        let mut dirty = BTreeSet::new();
+        for op in ops {
+            let voxel = match op {
+                VoxelOp::Insert { x, y, z, color } => {
+                    self.voxels.insert((x, y, z), color);
+                    (x, y, z)
+                }
+                VoxelOp::Remove { x, y, z } => {
+                    if self.voxels.remove(&(x, y, z)).is_none() {
+                        continue;
+                    }
+                    (x, y, z)
+                }
+                VoxelOp::Recolor { x, y, z, color } => {
+                    let Some(c) = self.voxels.get_mut(&(x, y, z)) else {
+                        continue;
+                    };
+                    *c = color;
+                    (x, y, z)
+                }
+            };
+            let lit = self.voxels.contains_key(&voxel);
+            for screen_idx in 0..NUM_SCREENS {
+                let Some(z_info) = self.codec.z_info(screen_idx, voxel.0, voxel.1, voxel.2) else {
+                    continue;
+                };
+                let key = (z_info.angle, screen_idx);
+                let bucket = self.buckets.entry(key).or_default();
+                if lit {
+                    bucket.insert(voxel);
+                } else {
+                    bucket.remove(&voxel);
+                }
+                dirty.insert(key);
+            }
+        }
+        for &(angle, screen_idx) in &dirty {
+            self.encode_bucket(angle, screen_idx);
+        }
+        dirty.into_iter().map(|(angle, _)| angle).collect()
+    }
+
+    fn encode_bucket(&mut self, angle: u32, screen_idx: usize) {
+        let mut addr_map: BTreeMap<ScreenLineAddr, ScreenLinePixels> = BTreeMap::new();
+        let key = (angle, screen_idx);
+        let mut stats = CollisionStats::default();
+        for &(x, y, z) in self.buckets.get(&key).into_iter().flatten() {
+            let z_info = self.codec.z_info(screen_idx, x, y, z).unwrap();
+            let color = self.codec.screen_color(screen_idx, self.voxels[&(x, y, z)]);
+            let rule = self.options.collision;
+            put_pixel(&mut addr_map, z_info, (x, y, z), color, rule, &mut stats);
+        }
+        if addr_map.is_empty() {
+            self.buckets.remove(&key);
+            self.collisions.remove(&key);
+            if let Some(lines_arr) = self.angle_map.get_mut(&angle) {
+                lines_arr[screen_idx].clear();
+                if lines_arr.iter().all(Vec::is_empty) {
+                    self.angle_map.remove(&angle);
+                }
+            }
+            return;
+        }
+        let lines = parse_addr_map(addr_map, &self.options, &mut stats);
+        self.collisions.insert(key, stats);
+        self.angle_map.entry(angle).or_default()[screen_idx] = lines;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Rgb;
+    use crate::driver::PlainShiftRegister;
+
+    #[test]
+    fn test_matches_full_encode() {
+        let codec = Codec::new();
+        let mut encoder = IncrementalEncoder::new(&codec, &PlainShiftRegister);
+        let ops = crate::pyramid_surface()
+            .into_iter()
+            .map(|(x, y, (z, color))| VoxelOp::Insert { x, y, z, color });
+        encoder.apply(ops);
+        assert_eq!(
+            *encoder.angle_map(),
+            codec.encode(&encoder.surface(), &PlainShiftRegister)
+        );
+
+        // move a small sprite across the pyramid
+        for step in 0..4u32 {
+            let mut ops = vec![];
+            for dy in 0..3 {
+                let (y, z) = (20 + dy, 30);
+                if step > 0 {
+                    ops.push(VoxelOp::Remove { x: 9 + step, y, z });
+                }
+                for dx in 0..3 {
+                    let x = 10 + step + dx;
+                    ops.push(VoxelOp::Insert {
+                        x,
+                        y,
+                        z,
+                        color: Rgb::new(0, 0xff, 0),
+                    });
+                }
+            }
+            ops.push(VoxelOp::Recolor {
+                x: 32,
+                y: 32,
+                z: 0,
+                color: Rgb::new(step as u8, 0, 0),
+            });
+            encoder.apply(ops);
+            assert_eq!(
+                *encoder.angle_map(),
+                codec.encode(&encoder.surface(), &PlainShiftRegister)
+            );
+        }
+
+        for (x, y, (z, _)) in encoder.surface() {
+            encoder.remove(x, y, z);
+        }
+        assert!(encoder.angle_map().is_empty());
+    }
+}
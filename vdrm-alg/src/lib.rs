@@ -1,6 +1,30 @@
-use geo::{ClosestPoint, EuclideanDistance, EuclideanLength, LineInterpolatePoint};
+use assign::{AssignStats, ScreenPolicy};
+use collision::{CollisionRule, CollisionStats, PixelSource};
+use driver::LedDriverProfile;
+use geo::{ClosestPoint, EuclideanDistance};
+use geometry::{FacetError, Geometry, SlotsError};
+use power::{BudgetStats, PowerBudget};
 use std::collections::BTreeMap;
 
+pub mod assign;
+pub mod calibration;
+pub mod collision;
+pub mod color;
+pub mod container;
+pub mod delta;
+pub mod driver;
+pub mod dwell;
+pub mod frame;
+pub mod geometry;
+pub mod hub75;
+pub mod incremental;
+pub mod pack;
+pub mod power;
+pub mod scan_order;
+pub mod schedule;
+pub mod solver;
+pub mod sparse;
+
 pub const W_PIXELS: usize = 64;
 pub const H_PIXELS: usize = 40;
 // pub const W_PIXELS: usize = 192;
@@ -16,6 +40,7 @@ pub const SCREEN_OFFSET: f32 = 0.05;
 pub const SCREEN_Z_OFFSET: f32 = -1.0 + SCREEN_OFFSET;
 pub const SCREEN_Y_OFFSET: f32 = -1.0 + SCREEN_OFFSET;
 // 八边形就x8 越大越清晰
+/// Angle slots per turn of the default `geometry::AngleSlots`.
 pub const TOTAL_ANGLES: usize = W_PIXELS * 8;
 
 // 点顺时针
@@ -56,8 +81,7 @@ pub fn rotate_x_rev(v: (f32, f32)) -> (f32, f32) {
 
 // pub const TOTAL_ANGLES: usize = 360;
 
-type PixelColor = u32;
-type PixelXY = (u32, u32);
+pub type PixelColor = color::Rgb;
 pub type PixelSurface = Vec<(u32, u32, (u32, PixelColor))>;
 pub type FloatSurface = Vec<(f32, f32, f32)>;
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
@@ -68,12 +92,14 @@ struct ScreenLineAddr {
 #[derive(Debug, Copy, Clone)]
 struct ScreenLinePixels {
     pixels: [Option<PixelColor>; W_PIXELS],
+    sources: [Option<PixelSource>; W_PIXELS],
 }
 
 impl Default for ScreenLinePixels {
     fn default() -> Self {
         Self {
             pixels: [None; W_PIXELS],
+            sources: [None; W_PIXELS],
         }
     }
 }
@@ -95,6 +121,8 @@ fn cacul_v_img_cord() -> glam::Vec4 {
 }
 
 pub const NUM_SCREENS: usize = 3;
+/// Facets of the mirror rotor.
+pub const NUM_FACETS: usize = 8;
 
 lazy_static::lazy_static! {
     pub static ref V_IMG_CORD: glam::Vec4 = {
@@ -164,12 +192,16 @@ struct ScreenPixel {
 #[derive(Clone, Copy, Debug)]
 struct PixelZInfo {
     angle: u32,
-    pixel: u32,
-    is_borrowed: bool,
     screen_pixel: ScreenPixel,
 }
 
+/// Serialized in the sparse form of [`sparse::SparseLine`].
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "sparse::SparseLine", from = "sparse::SparseLine")
+)]
 pub struct ScreenLine {
     pub screen_idx: usize,
     pub addr: u32,
@@ -181,7 +213,8 @@ pub type AngleMap = BTreeMap<u32, [Vec<ScreenLine>; NUM_SCREENS]>;
 type PixelZInfoList = [Option<PixelZInfo>; H_PIXELS];
 type PixelXYArr = Vec<Vec<PixelZInfoList>>;
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Screen {
     pub points: [(f32, f32, f32); 4],
 }
@@ -201,9 +234,37 @@ impl Screen {
     }
 }
 
+/// Settings of [`Codec::encode_with`].
+#[derive(Clone, Copy)]
+pub struct EncodeOptions<'a> {
+    pub driver: &'a dyn LedDriverProfile,
+    pub screen_policy: ScreenPolicy,
+    pub collision: CollisionRule,
+    /// Current limits applied to the whole frame, see [`power::limit`].
+    pub power: Option<PowerBudget>,
+}
+
+impl<'a> EncodeOptions<'a> {
+    pub fn new(driver: &'a dyn LedDriverProfile) -> Self {
+        Self {
+            driver,
+            screen_policy: ScreenPolicy::default(),
+            collision: CollisionRule::default(),
+            power: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncodeStats {
+    pub assign: AssignStats,
+    pub collisions: CollisionStats,
+    pub power: BudgetStats,
+}
+
 pub fn pixel_surface_to_float(pixel_surface: &PixelSurface) -> FloatSurface {
     pixel_surface
-        .into_iter()
+        .iter()
         .map(|&(pixel_x, pixel_y, (pixel_z, _color))| {
             let x = pixel_to_v(pixel_x);
             let y = pixel_to_v(pixel_y);
@@ -272,11 +333,10 @@ pub fn angle_to_v(p: u32) -> f32 {
 
 fn cacl_view_point(
     mat: glam::Mat4,
-    screen_idx: usize,
+    screen: &Screen,
     addr: u32,
     pixel_z: u32,
 ) -> ((f32, f32, f32), (f32, f32, f32)) {
-    let screen = &SCREENS[screen_idx];
     let fraction = addr as f32 / W_PIXELS as f32;
     let fraction_z = pixel_z as f32 / W_PIXELS as f32;
     let p_o = glam::Vec3::from(screen.points[0]);
@@ -301,17 +361,54 @@ fn closest_len(line: &geo::Line<f32>, p: &geo::Point<f32>) -> f32 {
     close_p.euclidean_distance(p)
 }
 
+/// Whether the virtual image at mirror transform `mat` is close enough to
+/// `screen` for the screen to light any voxel.
+fn faces_screen(mat: glam::Mat4, screen: &Screen) -> bool {
+    // 虚像的中心
+    let center = mat * *V_IMG_CENTER_CORD;
+    // 虚像对应实际的和屏幕接触的中心
+    let center_xy = geo::Point::new(center.x, center.y);
+    let xy_line = geo::Line::new(
+        (screen.points[0].0, screen.points[0].1),
+        (screen.points[3].0, screen.points[3].1),
+    );
+    closest_len(&xy_line, &center_xy) <= (2f32 * SCREEN_ZOOM).sqrt()
+}
+
+/// Mirror transform while panel line `addr` of angle slot `slot` is lit,
+/// see `Geometry::timing`.
+fn line_mat4(geometry: &Geometry, slot: u32, addr: u32) -> glam::Mat4 {
+    let facet = &geometry.facets[geometry.slots.facet(slot)];
+    mirror_mat4_facet(geometry.line_angle(slot, addr), facet)
+}
+
 fn mirror_mat4(angle_f: f32) -> glam::Mat4 {
-    let sin = -angle_f.sin();
-    let cos = -angle_f.cos();
+    mirror_mat4_facet(angle_f, &FacetError::default())
+}
+
+/// `mirror_mat4` of a facet with its build errors.
+fn mirror_mat4_facet(angle_f: f32, facet: &FacetError) -> glam::Mat4 {
+    let angle_f = angle_f + facet.azimuth;
+    let offset = MIRROR_OFFSET + facet.offset;
+    let (tilt_sin, tilt_cos) = facet.tilt.sin_cos();
+    // facet normal, tilted out of the rotation plane by the tilt error
+    let sin = -angle_f.sin() * tilt_cos;
+    let cos = -angle_f.cos() * tilt_cos;
+    let up = tilt_sin;
     let sin2 = sin * sin;
     let cos2 = cos * cos;
+    let up2 = up * up;
     let sin_cos = sin * cos;
     let mat_mir = glam::Mat4::from_cols(
-        glam::Vec4::new(sin2 - cos2, -2.0 * sin_cos, 0., 0.),
-        glam::Vec4::new(-2.0 * sin_cos, cos2 - sin2, 0., 0.),
-        glam::Vec4::new(0., 0., 1., 0.),
-        glam::Vec4::new(2.0 * MIRROR_OFFSET * cos, 2.0 * MIRROR_OFFSET * sin, 0., 1.),
+        glam::Vec4::new(sin2 + up2 - cos2, -2.0 * sin_cos, -2.0 * cos * up, 0.),
+        glam::Vec4::new(-2.0 * sin_cos, cos2 + up2 - sin2, -2.0 * sin * up, 0.),
+        glam::Vec4::new(-2.0 * cos * up, -2.0 * sin * up, 1. - 2.0 * up2, 0.),
+        glam::Vec4::new(
+            2.0 * offset * cos,
+            2.0 * offset * sin,
+            2.0 * offset * up,
+            1.,
+        ),
     );
     let mat_ratate_x = glam::Mat4::from_cols(
         glam::Vec4::new(1.0, 0.0, 0.0, 0.),
@@ -326,8 +423,7 @@ fn mirror_mat4(angle_f: f32) -> glam::Mat4 {
         glam::Vec4::new(0.0, ro_rev.y_axis.x, ro_rev.y_axis.y, 0.),
         glam::Vec4::new(0.0, 0., 0., 1.),
     );
-    let mat = mat_ratate_x_rev * mat_mir * mat_ratate_x;
-    mat
+    mat_ratate_x_rev * mat_mir * mat_ratate_x
 }
 
 pub fn mirror_points_f(angle_f: f32, points: &[(f32, f32, f32)]) -> Vec<(f32, f32, f32)> {
@@ -347,29 +443,68 @@ pub fn mirror_points(angle: u32, points: &[(f32, f32, f32)]) -> Vec<(f32, f32, f
     mirror_points_f(angle_f, points)
 }
 
+/// Effect of `Geometry::dead_pixels` on the voxel table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeadPixelReport {
+    /// Table entries dropped because they landed on a dead pixel.
+    pub masked: usize,
+    /// Voxels whose entry on some screen differs from the table without dead
+    /// pixels, but that are still lit from another angle or screen.
+    pub rerouted: usize,
+    /// Voxels that lost every entry, sorted.
+    pub unreachable: Vec<(u32, u32, u32)>,
+}
+
 pub struct Codec {
     xy_arrs: [PixelXYArr; NUM_SCREENS],
     mat_map: BTreeMap<u32, glam::Mat4>,
+    geometry: Geometry,
+    dead_pixel_report: DeadPixelReport,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Codec {
-    // TODO map screens to image and fill tthe xy_arr
     pub fn new() -> Self {
+        Self::with_geometry(Geometry::default())
+    }
+
+    /// Codec of `geometry`.
+    ///
+    /// # Panics
+    /// If `geometry.slots` fails `AngleSlots::check`, see
+    /// [`Codec::try_with_geometry`].
+    pub fn with_geometry(geometry: Geometry) -> Self {
+        match Self::try_with_geometry(geometry) {
+            Ok(codec) => codec,
+            Err(err) => panic!("invalid geometry: {err}"),
+        }
+    }
+
+    /// Codec of `geometry`, fails on invalid angle slots.
+    // TODO map screens to image and fill tthe xy_arr
+    pub fn try_with_geometry(geometry: Geometry) -> Result<Self, SlotsError> {
+        geometry.slots.check()?;
         // 初始化坐标map key是xyz虚像自己的相对坐标
         let mut xy_arrs = [PixelXYArr::new(), PixelXYArr::new(), PixelXYArr::new()];
         for _x in 0..W_PIXELS {
             let mut line = vec![];
-            line.extend(
-                std::iter::repeat(())
-                    .take(W_PIXELS)
-                    .map(|_| [None; H_PIXELS]),
-            );
+            line.extend(std::iter::repeat_n([None; H_PIXELS], W_PIXELS));
             xy_arrs[0].push(line.clone());
             xy_arrs[1].push(line.clone());
             xy_arrs[2].push(line);
         }
         let mut mat_map = BTreeMap::new();
-        let screen_metas: Vec<_> = SCREENS
+        let mut dead_pixel_report = DeadPixelReport::default();
+        // (screen, voxel) whose last entry, the one the table keeps without
+        // dead pixels, is on a dead pixel
+        let mut last_dead = std::collections::BTreeSet::new();
+        let screen_metas: Vec<_> = geometry
+            .screens
             .iter()
             .map(|screen| {
                 let fraction = 1f32 / W_PIXELS as f32;
@@ -389,55 +524,53 @@ impl Codec {
                 (screen, p_o, v_oa, v_ob)
             })
             .collect();
-        for angle in 0..TOTAL_ANGLES {
-            let angle = angle as u32;
-            let angle_f = angle_to_v(angle);
-            let mat = mirror_mat4(angle_f);
-            mat_map.insert(angle, mat);
-
-            // 虚像的中心
-            let center = mat * *V_IMG_CENTER_CORD;
-            // 虚像对应实际的和屏幕接触的中心
-            let center_xy = geo::Point::new(center.x, center.y);
-
-            let dbg = angle == (TOTAL_ANGLES as u32 / 4);
+        for angle in 0..geometry.slots.len() {
+            let angle_f = geometry.slots.angle(angle);
+            let mat = mirror_mat4_facet(angle_f, &geometry.facets[geometry.slots.facet(angle)]);
+            let key = geometry.angle_key(angle);
+            mat_map.insert(key, mat);
+
+            let dbg = angle == geometry.slots.len() / 4;
             // Vec4(0.0, 1.0, 1.0, 1.0) v_oa Vec4(0.0, 0.0, 0.03125, 1.0) v_ob Vec4(0.0, 0.03125, 0.0, 1.0)
 
             for (screen_idx, (screen, p_o, v_oa, v_ob)) in
                 screen_metas.clone().into_iter().enumerate()
             {
                 // 过滤掉太远的角度 减小计算量
-                let xy_line = geo::Line::new(
-                    (screen.points[0].0, screen.points[0].1),
-                    (screen.points[3].0, screen.points[3].1),
-                );
-                let closest_len = closest_len(&xy_line, &center_xy);
-                // log::info!("angle {angle} closest_len {closest_len}");
-                if closest_len > (2f32 * SCREEN_ZOOM).sqrt() {
+                if !faces_screen(mat, screen) {
                     continue;
                 }
 
-                let v_o = mat * glam::Vec4::new(0.0, 0.0, 0.0, 1.0);
                 if dbg {
                     log::info!("angle_f {angle_f}");
                     log::info!("mat {mat}");
                 }
-
                 // 把斜着放的屏幕的向量映射到虚像空间 实际上是二维坐标变换
-                let p_o = mat * p_o;
-                let v_oa = mat * v_oa;
-                let v_ob = mat * v_ob;
+                let to_image = |mat: glam::Mat4| {
+                    let v_o = mat * glam::Vec4::new(0.0, 0.0, 0.0, 1.0);
+                    (mat * p_o, mat * v_oa - v_o, mat * v_ob - v_o)
+                };
+                let (p_o, v_oa, v_ob) = to_image(mat);
                 if dbg {
                     log::info!("p_o {p_o:?} v_oa {v_oa:?} v_ob {v_ob:?}");
                 }
-                let v_oa = v_oa - v_o;
-                let v_ob = v_ob - v_o;
-                if dbg {
-                    log::info!("new v_o {v_o} v_oa {v_oa:?} v_ob {v_ob:?}");
-                }
+                // lines lit later in the slot see a turned mirror
+                let line_frames: Option<Vec<_>> = (!geometry.timing.is_zero()).then(|| {
+                    (0..W_PIXELS as u32)
+                        .map(|addr| to_image(line_mat4(&geometry, angle, addr)))
+                        .collect()
+                });
                 // 计算屏幕上每一个点对应虚像自己坐标的位置
                 for i in 0..W_PIXELS {
                     for j in 0..W_PIXELS {
+                        let mount = geometry.mounts[screen_idx];
+                        let Some((addr, pixel)) = mount.to_panel(j as u32, i as u32) else {
+                            continue;
+                        };
+                        let (p_o, v_oa, v_ob) = match &line_frames {
+                            Some(frames) => frames[addr as usize],
+                            None => (p_o, v_oa, v_ob),
+                        };
                         let p = p_o + v_oa * (i as f32) + v_ob * (j as f32);
                         let dbg = dbg && (i < 10 && j < 10);
                         if dbg {
@@ -452,21 +585,40 @@ impl Codec {
                         if dbg {
                             log::info!("x {x} y {y} z {z}");
                         }
+                        if geometry.dead_pixels[screen_idx].contains(&(addr, pixel)) {
+                            dead_pixel_report.masked += 1;
+                            last_dead.insert((screen_idx, x, y, z));
+                            continue;
+                        }
                         let z_point = PixelZInfo {
-                            angle,
-                            pixel: z,
-                            is_borrowed: false,
+                            angle: key,
                             screen_pixel: ScreenPixel {
                                 idx: screen_idx,
-                                addr: j as u32,
-                                pixel: i as u32,
+                                addr,
+                                pixel,
                             },
                         };
+                        last_dead.remove(&(screen_idx, x, y, z));
                         xy_arrs[screen_idx][x as usize][y as usize][z as usize] = Some(z_point);
                     }
                 }
             }
         }
+        // voxels of dead pixels keep the last live entry of an other angle
+        let changed: std::collections::BTreeSet<_> = last_dead
+            .into_iter()
+            .map(|(_, x, y, z)| (x, y, z))
+            .collect();
+        for (x, y, z) in changed {
+            let reachable = xy_arrs
+                .iter()
+                .any(|xy_arr| xy_arr[x as usize][y as usize][z as usize].is_some());
+            if reachable {
+                dead_pixel_report.rerouted += 1;
+            } else {
+                dead_pixel_report.unreachable.push((x, y, z));
+            }
+        }
         // TODO borrow value from other coloums
         // not good
 
@@ -516,40 +668,134 @@ impl Codec {
         //         }
         //     }
         // }
-        Self { xy_arrs, mat_map }
+        Ok(Self {
+            xy_arrs,
+            mat_map,
+            geometry,
+            dead_pixel_report,
+        })
+    }
+
+    pub fn geometry(&self) -> &Geometry {
+        &self.geometry
+    }
+
+    /// Mirror transform of the `AngleMap` key `angle` including the facet
+    /// errors, at the start of the slot before any `Geometry::timing` delay.
+    pub fn mirror_mat(&self, angle: u32) -> glam::Mat4 {
+        self.mat_map[&(angle % self.geometry.slots.len())]
+    }
+
+    pub fn dead_pixel_report(&self) -> &DeadPixelReport {
+        &self.dead_pixel_report
+    }
+
+    /// Panics if `driver` can't be used with the line timing, see
+    /// [`Codec::encode_with`].
+    fn check_driver(&self, driver: &dyn LedDriverProfile) {
+        assert!(
+            self.geometry.timing.is_zero() || !driver::groups_lines(driver, W_PIXELS),
+            "line timing can't be compensated after scan line grouping"
+        );
+    }
+
+    /// `color` after the color calibration of the screen.
+    fn screen_color(&self, screen_idx: usize, color: PixelColor) -> PixelColor {
+        self.geometry.calibration[screen_idx].apply(color)
+    }
+
+    fn z_info(&self, screen_idx: usize, x: u32, y: u32, z: u32) -> Option<PixelZInfo> {
+        let z_info_list = self.xy_arrs[screen_idx]
+            .get(x as usize)
+            .and_then(|v| v.get(y as usize))?;
+        // fix z offset
+        // TODO find the reason for offset
+        // let z = if z > 1 { z - 1 } else { z };
+        z_info_list.get(z as usize).and_then(|v| *v)
+    }
+
+    /// Encode through every screen that reaches a voxel.
+    ///
+    /// # Panics
+    /// See [`Codec::encode_with`].
+    pub fn encode(&self, pixel_surface: &PixelSurface, driver: &dyn LedDriverProfile) -> AngleMap {
+        self.encode_with(pixel_surface, &EncodeOptions::new(driver))
+            .0
+    }
+
+    /// Like [`Codec::encode`] but only through the screens set in `screen_mask`.
+    ///
+    /// # Panics
+    /// See [`Codec::encode_with`].
+    pub fn encode_screens(
+        &self,
+        pixel_surface: &PixelSurface,
+        screen_mask: [bool; NUM_SCREENS],
+        driver: &dyn LedDriverProfile,
+    ) -> AngleMap {
+        let masks = vec![screen_mask; pixel_surface.len()];
+        let options = EncodeOptions::new(driver);
+        self.encode_masks(pixel_surface, &masks, &options, &mut Default::default())
     }
 
-    pub fn encode(
+    /// Encode with the screens of each voxel picked by `options.screen_policy`,
+    /// pixel collisions resolved by `options.collision` and the current
+    /// limited by `options.power`.
+    ///
+    /// # Panics
+    /// If `Geometry::timing` is set and `options.driver` moves pixels to
+    /// other lines of their scan group: a moved pixel is lit at the time of
+    /// its new line but keeps the compensation of its old one.
+    pub fn encode_with(
         &self,
         pixel_surface: &PixelSurface,
-        pixel_offset: i32,
-        optimze_speed_for_mbi5264: bool,
+        options: &EncodeOptions,
+    ) -> (AngleMap, EncodeStats) {
+        let masks = assign::assign(self, pixel_surface, options.screen_policy);
+        let mut collisions = CollisionStats::default();
+        let mut angle_map = self.encode_masks(pixel_surface, &masks, options, &mut collisions);
+        let power = match &options.power {
+            Some(budget) => power::limit(&mut angle_map, budget, self.geometry.slots.len()),
+            None => BudgetStats::default(),
+        };
+        let stats = EncodeStats {
+            assign: AssignStats::new(&masks, &angle_map),
+            collisions,
+            power,
+        };
+        (angle_map, stats)
+    }
+
+    /// `masks[i]` are the screens lighting voxel `i` of the surface.
+    fn encode_masks(
+        &self,
+        pixel_surface: &PixelSurface,
+        masks: &[[bool; NUM_SCREENS]],
+        options: &EncodeOptions,
+        stats: &mut CollisionStats,
     ) -> AngleMap {
+        self.check_driver(options.driver);
         let mut angle_map: BTreeMap<
             u32,
             [BTreeMap<ScreenLineAddr, ScreenLinePixels>; NUM_SCREENS],
         > = BTreeMap::new();
-        for &(x, y, (z, color)) in pixel_surface {
+        for (&(x, y, (z, color)), mask) in pixel_surface.iter().zip(masks) {
             for screen_idx in 0..NUM_SCREENS {
-                let z_info_list = &self.xy_arrs[screen_idx][x as usize][y as usize];
-                // fix z offset
-                // TODO find the reason for offset
-                // let z = if z > 1 { z - 1 } else { z };
-                let Some(z_info) = z_info_list.get(z as usize).and_then(|v| *v) else {
+                if !mask[screen_idx] {
+                    continue;
+                }
+                let Some(z_info) = self.z_info(screen_idx, x, y, z) else {
                     continue;
                 };
                 let entry = angle_map.entry(z_info.angle).or_default();
-                let addr = ScreenLineAddr {
-                    screen_idx: z_info.screen_pixel.idx,
-                    addr: z_info.screen_pixel.addr,
-                };
-                let line_pixels = entry[screen_idx].entry(addr).or_default();
-                let pixel_idx = z_info.screen_pixel.pixel as usize;
-                if let Some(c) = line_pixels.pixels.get_mut(pixel_idx) {
-                    *c = Some(color);
-                } else {
-                    panic!("{x}, {y}, {z}, pixel_idx {pixel_idx}");
-                }
+                put_pixel(
+                    &mut entry[screen_idx],
+                    z_info,
+                    (x, y, z),
+                    self.screen_color(screen_idx, color),
+                    options.collision,
+                    stats,
+                );
             }
         }
         angle_map
@@ -557,9 +803,7 @@ impl Codec {
             .map(|(k, addr_maps)| {
                 (
                     k,
-                    addr_maps.map(|addr_map| {
-                        parse_addr_map(addr_map, pixel_offset, optimze_speed_for_mbi5264)
-                    }),
+                    addr_maps.map(|addr_map| parse_addr_map(addr_map, options, stats)),
                 )
             })
             .collect()
@@ -573,11 +817,18 @@ impl Codec {
             pixels,
         } in lines
         {
-            for (idx, pixel) in pixels.into_iter().enumerate() {
+            for (idx, pixel) in pixels.iter().enumerate() {
                 let Some(_pixel) = pixel else { continue };
-                let pixel_z = idx as u32;
-                let mat = self.mat_map.get(&angle).unwrap();
-                let (view, led) = cacl_view_point(*mat, *screen_idx, *addr, pixel_z);
+                let mat = match self.geometry.timing.is_zero() {
+                    true => *self.mat_map.get(&angle).unwrap(),
+                    false => line_mat4(&self.geometry, self.geometry.key_angle(angle), *addr),
+                };
+                let mount = self.geometry.mounts[*screen_idx];
+                let Some((addr, pixel_z)) = mount.from_panel(*addr, idx as u32) else {
+                    continue;
+                };
+                let screen = &self.geometry.screens[*screen_idx];
+                let (view, led) = cacl_view_point(mat, screen, addr, pixel_z);
                 view_surface.push(view);
                 led_surface.push(led);
             }
@@ -598,102 +849,97 @@ impl Codec {
     }
 }
 
+fn put_pixel(
+    addr_map: &mut BTreeMap<ScreenLineAddr, ScreenLinePixels>,
+    z_info: PixelZInfo,
+    voxel: collision::Voxel,
+    color: PixelColor,
+    rule: CollisionRule,
+    stats: &mut CollisionStats,
+) {
+    let addr = ScreenLineAddr {
+        screen_idx: z_info.screen_pixel.idx,
+        addr: z_info.screen_pixel.addr,
+    };
+    let line_pixels = addr_map.entry(addr).or_default();
+    let pixel_idx = z_info.screen_pixel.pixel as usize;
+    let mut source = PixelSource::new(voxel, addr.addr, color);
+    if let Some(old) = line_pixels.sources[pixel_idx] {
+        source = rule.merge(old, source, stats);
+    }
+    line_pixels.sources[pixel_idx] = Some(source);
+    line_pixels.pixels[pixel_idx] = Some(source.color);
+}
+
 fn parse_addr_map(
     mut addr_map: BTreeMap<ScreenLineAddr, ScreenLinePixels>,
-    pixel_offset: i32,
-    optimze_speed_for_mbi5264: bool,
+    options: &EncodeOptions,
+    stats: &mut CollisionStats,
 ) -> Vec<ScreenLine> {
-    let mut pixels_info: [Option<([u8; 4], ScreenLineAddr)>; W_PIXELS] = [None; W_PIXELS];
-    for (addr, line) in addr_map.iter_mut() {
-        for (color, pixel_info) in line.pixels.iter_mut().zip(&mut pixels_info) {
-            let Some(rgbh) = color.as_ref() else {
+    // one lit line per pixel column
+    let mut winners: [Option<PixelSource>; W_PIXELS] = [None; W_PIXELS];
+    for line in addr_map.values() {
+        for (source, winner) in line.sources.iter().zip(&mut winners) {
+            let Some(source) = *source else {
                 continue;
             };
-            let [r, g, b, _a] = rgbh.to_ne_bytes();
-            match pixel_info {
-                Some(_rgbh) => {
-                    *color = None;
-                }
-                None => {
-                    *pixel_info = Some(([r, g, b, addr.addr as u8], *addr));
-                }
-            }
+            *winner = Some(match *winner {
+                Some(old) => options.collision.merge(old, source, stats),
+                None => source,
+            });
         }
     }
-    if optimze_speed_for_mbi5264 {
-        for i in 0..64usize {
-            let region0 = i;
-            let region1 = i + 64;
-            let region2 = i + 128;
-            let regions = [region1, region0, region2];
-            let mut non_empty_h: Option<u8> = None;
-            for region in regions {
-                let Some(pixel_info) = pixels_info[region] else {
-                    continue;
-                };
-                if pixel_info.0[..3] == [0; 3] {
-                    continue;
-                }
-                non_empty_h = Some(pixel_info.0[3]);
-                break;
-            }
-            let Some(non_empty_h) = non_empty_h else {
+    let mut pixels_info: [Option<([u8; 4], ScreenLineAddr)>; W_PIXELS] = [None; W_PIXELS];
+    for (addr, line) in addr_map.iter_mut() {
+        for ((color, winner), pixel_info) in
+            line.pixels.iter_mut().zip(&winners).zip(&mut pixels_info)
+        {
+            let Some(winner) = winner.filter(|w| w.addr == addr.addr) else {
+                *color = None;
                 continue;
             };
-            let non_empty_h_mod = non_empty_h % 16;
-            for region in regions {
-                let Some((rgbh, line_addr)) = &pixels_info[region] else {
-                    continue;
-                };
-                let h = rgbh[3];
-                let h_mod = h % 16;
-                if h_mod == non_empty_h_mod {
-                    continue;
-                }
-                let mut deta = non_empty_h_mod as i16 - h_mod as i16;
-                if deta.abs() > 8 {
-                    let try_deta = if deta > 0 { deta - 16 } else { deta + 16 };
-                    let try_h = h as i16 + try_deta as i16;
-                    if try_h >= 0 && try_h <= 143 {
-                        deta = try_deta;
-                    }
-                }
-                let new_h = (h as i16 + deta) as u8;
-                let line_pixels = addr_map.get_mut(&line_addr).unwrap();
-                let pixel = line_pixels.pixels[region].take().unwrap();
-                let mut line_addr = *line_addr;
-                line_addr.addr = new_h as u32;
-                let entry = addr_map.entry(line_addr).or_default();
-                entry.pixels[region] = Some(pixel);
-            }
+            *color = Some(winner.color);
+            let color::Rgb { r, g, b } = winner.color;
+            *pixel_info = Some(([r, g, b, addr.addr as u8], *addr));
         }
     }
+    driver::group_scan_lines(options.driver, &mut addr_map, &pixels_info);
 
     addr_map
         .into_iter()
-        .map(|(k, mut v)| {
-            if pixel_offset > 0 {
-                let offset = pixel_offset as usize;
-                v.pixels.rotate_right(offset);
-                v.pixels.iter_mut().take(offset).for_each(|v| {
-                    v.take();
-                });
-            } else if pixel_offset < 0 {
-                let offset = (-pixel_offset) as usize;
-                v.pixels.rotate_left(offset);
-                v.pixels.iter_mut().rev().take(offset).for_each(|v| {
-                    v.take();
-                });
-            }
-            ScreenLine {
-                screen_idx: k.screen_idx,
-                addr: k.addr,
-                pixels: v.pixels,
-            }
+        .map(|(k, v)| ScreenLine {
+            screen_idx: k.screen_idx,
+            addr: k.addr,
+            pixels: v.pixels,
         })
         .collect::<Vec<_>>()
 }
 
+/// Pyramid with its apex at the panel centre and a color per quadrant,
+/// shared by the tests.
+#[cfg(test)]
+pub(crate) fn pyramid_surface() -> PixelSurface {
+    let r = W_PIXELS as i32 / 2;
+    let mut surface = vec![];
+    for x in 0..W_PIXELS as u32 {
+        for y in 0..W_PIXELS as u32 {
+            let (dx, dy) = (x as i32 - r, y as i32 - r);
+            let h = dx.abs() + dy.abs();
+            if h >= H_PIXELS as i32 {
+                continue;
+            }
+            let color = match (dx >= 0, dy >= 0) {
+                (true, true) => color::Rgb::WHITE,
+                (false, true) => color::Rgb::new(0xff, 0, 0),
+                (false, false) => color::Rgb::new(0, 0xff, 0),
+                (true, false) => color::Rgb::new(0xff, 0, 0xff),
+            };
+            surface.push((x, y, (H_PIXELS as u32 - 1 - h as u32, color)));
+        }
+    }
+    surface
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -706,4 +952,43 @@ mod test {
         // p [-0.00000024726896, -2.9999998, -2.9999998, 1]
         println!("p {p}");
     }
+
+    /// Voxel a decoded view point falls into.
+    fn view_to_pixel((x, y, z): (f32, f32, f32)) -> Option<(u32, u32, u32)> {
+        v3_2_pixel(x, y - V_IMG_CORD.y + SCREEN_ZOOM, V_IMG_CORD.z - z)
+    }
+
+    #[test]
+    fn test_facet_error() {
+        let facet = FacetError {
+            tilt: 0.01,
+            azimuth: -0.02,
+            offset: 0.03,
+        };
+        let mat = mirror_mat4_facet(angle_to_v(120), &facet);
+        let linear = glam::Mat3::from_mat4(mat);
+        assert!((linear.determinant() + 1.).abs() < 1e-5);
+        assert!((linear * linear).abs_diff_eq(glam::Mat3::IDENTITY, 1e-5));
+
+        let mut geometry = Geometry::default();
+        geometry.facets[2] = facet;
+        let ideal = Codec::new();
+        let codec = Codec::with_geometry(geometry);
+        for angle in 0..TOTAL_ANGLES as u32 {
+            let same = ideal.mirror_mat(angle) == codec.mirror_mat(angle);
+            let facet_idx = codec.geometry().slots.facet(angle);
+            assert_eq!(same, facet_idx != 2, "angle {angle}");
+        }
+        let voxel = (32, 32, 20);
+        let surface = vec![(voxel.0, voxel.1, (voxel.2, color::Rgb::WHITE))];
+        let angle_map = codec.encode(&surface, &driver::PlainShiftRegister);
+        let (view, _) = codec.decode_all(angle_map);
+        assert!(!view.is_empty());
+        for p in view {
+            let (x, y, z) = view_to_pixel(p).unwrap();
+            assert!(
+                x.abs_diff(voxel.0) <= 1 && y.abs_diff(voxel.1) <= 1 && z.abs_diff(voxel.2) <= 1
+            );
+        }
+    }
 }
@@ -1,4 +1,9 @@
-use std::fmt::Display;
+use std::collections::BTreeSet;
+use vdrm_alg::color::Rgb;
+use vdrm_alg::driver::PlainShiftRegister;
+use vdrm_alg::frame::Frame;
+use vdrm_alg::pack::{pack, ConflictRule};
+use vdrm_alg::sparse;
 
 fn gen_pyramid_surface() -> vdrm_alg::PixelSurface {
     let mut pixel_surface = vdrm_alg::PixelSurface::new();
@@ -15,10 +20,10 @@ fn gen_pyramid_surface() -> vdrm_alg::PixelSurface {
             let z = vdrm_alg::H_PIXELS as u32 - 1 - h as u32;
             // let z = h as u32;
             let color = match (x_i32 >= 0, y_i32 >= 0) {
-                (true, true) => u32::from_ne_bytes([gray, gray, gray, 0]),
-                (false, true) => u32::from_ne_bytes([gray, 0, 0, 0]),
-                (false, false) => u32::from_ne_bytes([0, gray, 0, 0]),
-                (true, false) => u32::from_ne_bytes([gray, 0, gray, 0]),
+                (true, true) => Rgb::new(gray, gray, gray),
+                (false, true) => Rgb::new(gray, 0, 0),
+                (false, false) => Rgb::new(0, gray, 0),
+                (true, false) => Rgb::new(gray, 0, gray),
             };
             pixel_surface.push((x, y, (z, color)));
         }
@@ -26,89 +31,37 @@ fn gen_pyramid_surface() -> vdrm_alg::PixelSurface {
     pixel_surface
 }
 
-pub type RGBH = [u8; 4];
-pub const IMG_HEIGHT: usize = 192;
-pub const IMG_WIDTH: usize = IMG_HEIGHT / 4;
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct AngleImage {
-    pub angle: u32,
-    // rgbh
-    pub coloum: [RGBH; IMG_HEIGHT],
-}
-
-impl AngleImage {
-    pub const fn new(angle: u32) -> Self {
-        Self {
-            angle,
-            coloum: [[0; 4]; IMG_HEIGHT],
-        }
-    }
-}
-
-impl Display for AngleImage {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for rgbh in self.coloum {
-            let h = rgbh[3];
-            write!(f, "{h:02}")?;
-        }
-        write!(f, "\n")
-    }
-}
-
 fn dbg_codec() {
     let codec = vdrm_alg::Codec::new();
     let pyramid = gen_pyramid_surface();
-    let map = codec.encode(&pyramid, 0, false);
-    let mut angle_list = vec![];
-    let mut angles = vec![];
-    for (angle, lines) in map {
-        let mut img = AngleImage::new(angle);
-        let mut pixels: [Option<[u8; 4]>; IMG_HEIGHT] = [None; IMG_HEIGHT];
-        let [line, ..] = lines;
-        if line.is_empty() {
-            continue;
-        }
-        angles.push(angle);
-        for p in line {
-            for (color, pixel) in p.pixels.iter().zip(&mut pixels) {
-                let Some(color) = color else {
-                    continue;
-                };
-                let [r, g, b, _a] = color.to_ne_bytes();
-                match pixel {
-                    Some(rgbh) => {
-                        let h = rgbh[3];
-                        if p.addr < h as u32 {
-                            *rgbh = [r, g, b, p.addr as u8];
-                        }
-                    }
-                    None => {
-                        *pixel = Some([r, g, b, p.addr as u8]);
-                    }
-                }
-            }
-        }
-        for (c, p) in img.coloum.iter_mut().zip(pixels) {
-            if let Some(p) = p {
-                *c = p;
-            }
-        }
-        // print!("{} {}", img.angle, img);
-        angle_list.push(img);
-    }
+    let map = codec.encode(&pyramid, &PlainShiftRegister);
+    let angle_list = pack(&map, ConflictRule::LowestAddr).unwrap();
+    let angles: BTreeSet<u32> = angle_list.iter().map(|img| img.angle).collect();
     println!(
-        "angles {} [{}..{}]",
+        "angles {} [{}..{}] images {}",
         angles.len(),
         angles.first().unwrap(),
-        angles.last().unwrap()
+        angles.last().unwrap(),
+        angle_list.len()
     );
-    let buf = unsafe {
-        std::slice::from_raw_parts(
-            angle_list.as_ptr() as *const u8,
-            angle_list.len() * std::mem::size_of::<AngleImage>(),
-        )
+    let frame = Frame {
+        geometry_hash: codec.geometry().hash(),
+        images: angle_list,
     };
+    let buf = frame.to_bytes().unwrap();
+    println!("frame {} bytes", buf.len());
+}
+
+fn dbg_sparse() {
+    let codec = vdrm_alg::Codec::new();
+    let map = codec.encode(&gen_pyramid_surface(), &PlainShiftRegister);
+    let sparse_map = sparse::sparse(&map);
+    println!(
+        "pyramid dense {} bytes sparse {} bytes wire {} bytes",
+        sparse::dense_bytes(&map),
+        sparse::sparse_bytes(&sparse_map),
+        sparse::to_bytes(&sparse_map).len()
+    );
 }
 
 fn dbg_screens() {
@@ -127,4 +80,5 @@ fn dbg_screens() {
 fn main() {
     dbg_screens();
     dbg_codec();
+    dbg_sparse();
 }
@@ -0,0 +1,184 @@
+use crate::color::Rgb;
+use crate::frame::AngleImage;
+use crate::{AngleMap, ScreenLine, NUM_SCREENS, W_PIXELS};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PackError {
+    /// A line address does not fit the `u8` of a column pixel.
+    AddrRange { angle: u32, addr: u32 },
+    /// An image of a screen index of `NUM_SCREENS` or above.
+    BadScreen(usize),
+}
+
+impl Display for PackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackError::AddrRange { angle, addr } => {
+                write!(f, "line address {addr} at angle {angle} exceeds a byte")
+            }
+            PackError::BadScreen(idx) => write!(f, "screen index {idx} out of range"),
+        }
+    }
+}
+
+impl std::error::Error for PackError {}
+
+/// Which line wins when several lines of one screen light the same pixel
+/// column at one angle. A column image can only hold one address per pixel.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ConflictRule {
+    /// Keep the line with the lowest address.
+    #[default]
+    LowestAddr,
+    /// Keep the line with the highest address.
+    HighestAddr,
+    /// Keep the line that comes first in the `AngleMap`.
+    FirstLine,
+}
+
+/// Pack every non empty screen of every angle into one column image.
+///
+/// Dark pixels are `[0, 0, 0, 0]`. Fails on addresses above `u8::MAX`.
+pub fn pack(angle_map: &AngleMap, rule: ConflictRule) -> Result<Vec<AngleImage>, PackError> {
+    let mut images = vec![];
+    for (&angle, lines_arr) in angle_map {
+        for (screen_idx, lines) in lines_arr.iter().enumerate() {
+            if lines.is_empty() {
+                continue;
+            }
+            let mut pixels: [Option<[u8; 4]>; W_PIXELS] = [None; W_PIXELS];
+            for line in lines {
+                for (color, pixel) in line.pixels.iter().zip(&mut pixels) {
+                    let Some(color) = color else {
+                        continue;
+                    };
+                    let Rgb { r, g, b } = *color;
+                    let h = u8::try_from(line.addr).map_err(|_| PackError::AddrRange {
+                        angle,
+                        addr: line.addr,
+                    })?;
+                    let rgbh = [r, g, b, h];
+                    match pixel {
+                        Some(old) => {
+                            let h = old[3] as u32;
+                            let replace = match rule {
+                                ConflictRule::LowestAddr => line.addr < h,
+                                ConflictRule::HighestAddr => line.addr > h,
+                                ConflictRule::FirstLine => false,
+                            };
+                            if replace {
+                                *old = rgbh;
+                            }
+                        }
+                        None => *pixel = Some(rgbh),
+                    }
+                }
+            }
+            let mut img = AngleImage::new(angle, screen_idx);
+            for (c, p) in img.coloum.iter_mut().zip(pixels) {
+                if let Some(p) = p {
+                    *c = p;
+                }
+            }
+            images.push(img);
+        }
+    }
+    Ok(images)
+}
+
+/// Turn column images back into `ScreenLine`s, one line per used address.
+///
+/// Black pixels can not be told apart from dark ones and are dropped.
+pub fn unpack(images: &[AngleImage]) -> Result<AngleMap, PackError> {
+    let mut angle_map = AngleMap::new();
+    for img in images {
+        if img.screen_idx >= NUM_SCREENS {
+            return Err(PackError::BadScreen(img.screen_idx));
+        }
+        let mut lines: BTreeMap<u32, ScreenLine> = BTreeMap::new();
+        for (idx, &[r, g, b, h]) in img.coloum.iter().enumerate() {
+            let color = Rgb::new(r, g, b);
+            if color.is_black() {
+                continue;
+            }
+            let line = lines.entry(h as u32).or_insert(ScreenLine {
+                screen_idx: img.screen_idx,
+                addr: h as u32,
+                pixels: [None; W_PIXELS],
+            });
+            line.pixels[idx] = Some(color);
+        }
+        let entry = angle_map.entry(img.angle).or_default();
+        entry[img.screen_idx].extend(lines.into_values());
+    }
+    Ok(angle_map)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pack_conflict_rule() {
+        let red = Rgb::new(0xff, 0, 0);
+        let blue = Rgb::new(0, 0, 0xff);
+        let mut lines = vec![];
+        for (addr, color) in [(9, red), (4, blue)] {
+            let mut pixels = [None; W_PIXELS];
+            pixels[1] = Some(color);
+            pixels[addr as usize] = Some(color);
+            lines.push(ScreenLine {
+                screen_idx: 2,
+                addr,
+                pixels,
+            });
+        }
+        let mut angle_map = AngleMap::new();
+        angle_map.insert(5, [vec![], vec![], lines]);
+
+        let images = pack(&angle_map, ConflictRule::LowestAddr).unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!((images[0].angle, images[0].screen_idx), (5, 2));
+        assert_eq!(images[0].coloum[1], [0, 0, 0xff, 4]);
+        assert_eq!(images[0].coloum[9], [0xff, 0, 0, 9]);
+        assert_eq!(images[0].coloum[0], [0; 4]);
+        let images = pack(&angle_map, ConflictRule::HighestAddr).unwrap();
+        assert_eq!(images[0].coloum[1], [0xff, 0, 0, 9]);
+        let images = pack(&angle_map, ConflictRule::FirstLine).unwrap();
+        assert_eq!(images[0].coloum[1], [0xff, 0, 0, 9]);
+
+        let unpacked = unpack(&images).unwrap();
+        let lines = &unpacked[&5][2];
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].addr, 4);
+        assert_eq!(lines[0].pixels[1], None);
+        assert_eq!(lines[1].pixels[1], Some(red));
+        assert_eq!(pack(&unpacked, ConflictRule::LowestAddr), Ok(images));
+    }
+
+    #[test]
+    fn test_pack_errors() {
+        let mut pixels = [None; W_PIXELS];
+        pixels[0] = Some(Rgb::WHITE);
+        let line = ScreenLine {
+            screen_idx: 0,
+            addr: 256,
+            pixels,
+        };
+        let mut angle_map = AngleMap::new();
+        angle_map.insert(7, [vec![line], vec![], vec![]]);
+        assert_eq!(
+            pack(&angle_map, ConflictRule::LowestAddr),
+            Err(PackError::AddrRange {
+                angle: 7,
+                addr: 256
+            })
+        );
+
+        let mut img = AngleImage::new(0, NUM_SCREENS);
+        img.coloum[0] = [1, 2, 3, 4];
+        assert_eq!(unpack(&[img]), Err(PackError::BadScreen(NUM_SCREENS)));
+    }
+}
@@ -0,0 +1,298 @@
+//! Current draw of encoded frames and a budget limiting it.
+//!
+//! The rotor is fed through a slip ring, so the panels may only draw a
+//! limited current at any angle and on average over a turn. Currents are
+//! estimated per LED channel, scaled by its intensity.
+
+use crate::{AngleMap, PixelColor};
+use std::collections::BTreeMap;
+
+/// Current of the LEDs in mA.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CurrentModel {
+    /// Red, green and blue channel at full intensity.
+    pub channel_ma: [f32; 3],
+    /// Drivers and dark panels, drawn at every angle.
+    pub base_ma: f32,
+}
+
+impl Default for CurrentModel {
+    fn default() -> Self {
+        Self {
+            channel_ma: [20.0; 3],
+            base_ma: 0.0,
+        }
+    }
+}
+
+impl CurrentModel {
+    pub fn pixel_ma(&self, color: PixelColor) -> f32 {
+        color
+            .channels()
+            .iter()
+            .zip(self.channel_ma)
+            .map(|(&v, ma)| v as f32 / 255.0 * ma)
+            .sum()
+    }
+}
+
+/// Which pixels go first when a budget is exceeded.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum DropPriority {
+    /// Dimmest pixels first, keeps as much of the brightness as possible.
+    #[default]
+    Dimmest,
+    /// Brightest pixels first, keeps as many pixels as possible.
+    Brightest,
+}
+
+/// How a budget is enforced.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum BudgetAction {
+    /// Dim all pixels of an angle or turn, pixel caps still drop pixels.
+    #[default]
+    Scale,
+    /// Drop pixels by priority.
+    Drop,
+}
+
+/// Limits of [`limit`], `None` for no limit.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PowerBudget {
+    pub model: CurrentModel,
+    pub max_lit_per_angle: Option<usize>,
+    pub max_ma_per_angle: Option<f32>,
+    pub max_lit_per_turn: Option<usize>,
+    /// Mean current over a turn.
+    pub max_mean_ma: Option<f32>,
+    pub action: BudgetAction,
+    pub priority: DropPriority,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct BudgetStats {
+    /// Pixels dimmed, counted once per dimming.
+    pub scaled: usize,
+    /// Pixels dropped, including ones dimmed to black.
+    pub dropped: usize,
+}
+
+/// Estimated current of an `AngleMap`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CurrentReport {
+    /// mA of every angle with lit pixels.
+    pub per_angle: BTreeMap<u32, f32>,
+    pub peak_ma: f32,
+    /// Mean over a turn of `slots` angles, dark angles draw the base current.
+    pub mean_ma: f32,
+}
+
+/// Current draw of `angle_map` over a turn of `slots` angles.
+pub fn estimate(angle_map: &AngleMap, model: &CurrentModel, slots: u32) -> CurrentReport {
+    let mut report = CurrentReport::default();
+    let mut total = 0.0;
+    for (&angle, lines_arr) in angle_map {
+        let pixels_ma: f32 = lines_arr
+            .iter()
+            .flatten()
+            .flat_map(|line| line.pixels.iter().flatten())
+            .map(|&color| model.pixel_ma(color))
+            .sum();
+        total += pixels_ma;
+        let ma = model.base_ma + pixels_ma;
+        report.peak_ma = report.peak_ma.max(ma);
+        report.per_angle.insert(angle, ma);
+    }
+    report.mean_ma = model.base_ma + total / slots.max(1) as f32;
+    report
+}
+
+fn dim(pixels: &mut [&mut Option<PixelColor>], factor: f32, stats: &mut BudgetStats) {
+    for pixel in pixels.iter_mut() {
+        let color = pixel.unwrap().scale(factor);
+        stats.scaled += 1;
+        if color.is_black() {
+            **pixel = None;
+            stats.dropped += 1;
+        } else {
+            **pixel = Some(color);
+        }
+    }
+}
+
+/// Drop lit pixels by priority until at most `max_lit` are left drawing at
+/// most `max_ma`.
+fn drop_until(
+    pixels: Vec<&mut Option<PixelColor>>,
+    budget: &PowerBudget,
+    max_lit: usize,
+    max_ma: f32,
+    stats: &mut BudgetStats,
+) {
+    let mut pixels: Vec<_> = pixels
+        .into_iter()
+        .filter_map(|p| Some((budget.model.pixel_ma((*p)?), p)))
+        .collect();
+    pixels.sort_by(|a, b| match budget.priority {
+        DropPriority::Dimmest => a.0.total_cmp(&b.0),
+        DropPriority::Brightest => b.0.total_cmp(&a.0),
+    });
+    let mut lit = pixels.len();
+    let mut ma: f32 = pixels.iter().map(|p| p.0).sum();
+    for (pixel_ma, pixel) in pixels {
+        if lit <= max_lit && ma <= max_ma {
+            break;
+        }
+        *pixel = None;
+        lit -= 1;
+        ma -= pixel_ma;
+        stats.dropped += 1;
+    }
+}
+
+/// The lit ones of `colors`.
+fn lit_pixels<'a>(
+    colors: impl Iterator<Item = &'a mut Option<PixelColor>>,
+) -> Vec<&'a mut Option<PixelColor>> {
+    colors.filter(|p| p.is_some()).collect()
+}
+
+fn enforce(
+    mut pixels: Vec<&mut Option<PixelColor>>,
+    budget: &PowerBudget,
+    max_lit: Option<usize>,
+    max_ma: Option<f32>,
+    stats: &mut BudgetStats,
+) {
+    let max_lit = max_lit.unwrap_or(usize::MAX);
+    let max_ma = max_ma.unwrap_or(f32::INFINITY).max(0.0);
+    let ma: f32 = pixels
+        .iter()
+        .map(|p| budget.model.pixel_ma(p.unwrap()))
+        .sum();
+    if budget.action == BudgetAction::Scale && ma > max_ma {
+        dim(&mut pixels, max_ma / ma, stats);
+    }
+    // also catches rounding up while dimming
+    drop_until(pixels, budget, max_lit, max_ma, stats);
+}
+
+/// Dim or drop pixels of `angle_map` until it fits `budget` on a turn of
+/// `slots` angles. Per angle limits are enforced first, lines and angles
+/// left dark are removed.
+pub fn limit(angle_map: &mut AngleMap, budget: &PowerBudget, slots: u32) -> BudgetStats {
+    let mut stats = BudgetStats::default();
+    let base_ma = budget.model.base_ma;
+    let max_angle_ma = budget.max_ma_per_angle.map(|ma| ma - base_ma);
+    for lines_arr in angle_map.values_mut() {
+        let pixels = lit_pixels(
+            lines_arr
+                .iter_mut()
+                .flatten()
+                .flat_map(|line| line.pixels.iter_mut()),
+        );
+        enforce(
+            pixels,
+            budget,
+            budget.max_lit_per_angle,
+            max_angle_ma,
+            &mut stats,
+        );
+    }
+    let max_turn_ma = budget
+        .max_mean_ma
+        .map(|ma| (ma - base_ma) * slots.max(1) as f32);
+    let pixels = lit_pixels(
+        angle_map
+            .values_mut()
+            .flatten()
+            .flatten()
+            .flat_map(|line| line.pixels.iter_mut()),
+    );
+    enforce(
+        pixels,
+        budget,
+        budget.max_lit_per_turn,
+        max_turn_ma,
+        &mut stats,
+    );
+
+    for lines_arr in angle_map.values_mut() {
+        for lines in lines_arr {
+            lines.retain(|line| line.pixels.iter().any(Option::is_some));
+        }
+    }
+    angle_map.retain(|_, lines_arr| lines_arr.iter().any(|lines| !lines.is_empty()));
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Rgb;
+    use crate::driver::PlainShiftRegister;
+    use crate::{Codec, EncodeOptions, TOTAL_ANGLES};
+
+    fn lit(angle_map: &AngleMap) -> Vec<usize> {
+        angle_map
+            .values()
+            .map(|lines_arr| {
+                let lines = lines_arr.iter().flatten();
+                lines.map(|l| l.pixels.iter().flatten().count()).sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_power_budget() {
+        let codec = Codec::new();
+        let surface = crate::pyramid_surface();
+        let model = CurrentModel {
+            base_ma: 5.0,
+            ..Default::default()
+        };
+        let angle_map = codec.encode(&surface, &PlainShiftRegister);
+        let report = estimate(&angle_map, &model, TOTAL_ANGLES as u32);
+        assert_eq!(report.per_angle.len(), angle_map.len());
+        assert!(report.mean_ma < report.peak_ma);
+        assert_eq!(model.pixel_ma(Rgb::WHITE), 60.0);
+        let peak_lit = lit(&angle_map).into_iter().max().unwrap();
+
+        let max_ma = report.peak_ma / 2.0;
+        for action in [BudgetAction::Scale, BudgetAction::Drop] {
+            for priority in [DropPriority::Dimmest, DropPriority::Brightest] {
+                let budget = PowerBudget {
+                    model,
+                    max_lit_per_angle: Some(peak_lit / 2),
+                    max_ma_per_angle: Some(max_ma),
+                    max_mean_ma: Some(report.mean_ma / 3.0),
+                    action,
+                    priority,
+                    ..Default::default()
+                };
+                let options = EncodeOptions {
+                    power: Some(budget),
+                    ..EncodeOptions::new(&PlainShiftRegister)
+                };
+                let (limited, stats) = codec.encode_with(&surface, &options);
+                let limited_report = estimate(&limited, &model, TOTAL_ANGLES as u32);
+                assert!(limited_report.peak_ma <= max_ma + 1e-3);
+                assert!(limited_report.mean_ma <= report.mean_ma / 3.0 + 1e-3);
+                assert!(lit(&limited).into_iter().all(|n| n <= peak_lit / 2));
+                assert!(stats.power.dropped > 0);
+                assert_eq!(stats.power.scaled > 0, action == BudgetAction::Scale);
+            }
+        }
+
+        let mut one_turn = angle_map.clone();
+        let budget = PowerBudget {
+            max_lit_per_turn: Some(100),
+            action: BudgetAction::Drop,
+            ..Default::default()
+        };
+        let stats = limit(&mut one_turn, &budget, TOTAL_ANGLES as u32);
+        assert_eq!(lit(&one_turn).iter().sum::<usize>(), 100);
+        assert_eq!(stats.dropped + 100, lit(&angle_map).iter().sum::<usize>());
+        assert!(one_turn.len() < angle_map.len());
+    }
+}
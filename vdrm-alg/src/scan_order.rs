@@ -0,0 +1,283 @@
+//! Output order of the lines of an angle.
+//!
+//! `Codec::encode` emits lines in address order. Drivers that multiplex scan
+//! lines light all lines of one scan group in one step, and switching the
+//! row address between steps costs time on some panels. [`optimise`] merges
+//! the lines of a group into consecutive steps and orders the steps for the
+//! least switching time. The `AngleMap` keeps its address order, which
+//! `delta::apply`, `pack` and `Schedule::check` rely on, the output order is
+//! returned on its own as a [`LineOrder`].
+
+use crate::driver::LedDriverProfile;
+use crate::{AngleMap, ScreenLine, NUM_SCREENS};
+use std::collections::BTreeMap;
+
+/// Output order of every angle and screen as indices into the lines of the
+/// `AngleMap`.
+pub type LineOrder = BTreeMap<u32, [Vec<usize>; NUM_SCREENS]>;
+
+/// Cost of switching the row address from one scan step to the next.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum SwitchMetric {
+    /// Every change costs the same.
+    #[default]
+    Fixed,
+    /// Proportional to the address distance, e.g. shift register row drivers.
+    Distance,
+    /// Proportional to the changed address bits, e.g. HUB75 ABCDE lines.
+    Bits,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct ScanCost {
+    /// Time of one scan step.
+    pub line_us: f32,
+    /// Time of one unit of `metric`.
+    pub switch_us: f32,
+    pub metric: SwitchMetric,
+}
+
+impl ScanCost {
+    /// Time to switch between the scan addresses `from` and `to`.
+    pub fn switch(&self, from: u32, to: u32) -> f32 {
+        let units = match self.metric {
+            SwitchMetric::Fixed => (from != to) as u32,
+            SwitchMetric::Distance => from.abs_diff(to),
+            SwitchMetric::Bits => (from ^ to).count_ones(),
+        };
+        units as f32 * self.switch_us
+    }
+
+    /// Output time of scan addresses in order, repeated addresses are one step.
+    pub fn time(&self, scan_addrs: &[u32]) -> f32 {
+        let mut time = 0.0;
+        let mut last = None;
+        for &addr in scan_addrs {
+            match last {
+                Some(last) if last == addr => continue,
+                Some(last) => time += self.switch(last, addr) + self.line_us,
+                None => time += self.line_us,
+            }
+            last = Some(addr);
+        }
+        time
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct OrderStats {
+    pub lines: usize,
+    /// Scan steps after merging.
+    pub steps: usize,
+    pub time_before_us: f32,
+    pub time_after_us: f32,
+}
+
+impl std::ops::AddAssign for OrderStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.lines += rhs.lines;
+        self.steps += rhs.steps;
+        self.time_before_us += rhs.time_before_us;
+        self.time_after_us += rhs.time_after_us;
+    }
+}
+
+fn path_cost(cost: &ScanCost, path: &[u32]) -> f32 {
+    path.windows(2).map(|w| cost.switch(w[0], w[1])).sum()
+}
+
+/// Cost change of reversing `path[i..=j]`, only the two edges at its ends
+/// change as switching costs are symmetric.
+fn reverse_delta(cost: &ScanCost, path: &[u32], i: usize, j: usize) -> f32 {
+    let mut delta = 0.0;
+    if i > 0 {
+        delta += cost.switch(path[i - 1], path[j]) - cost.switch(path[i - 1], path[i]);
+    }
+    if j + 1 < path.len() {
+        delta += cost.switch(path[i], path[j + 1]) - cost.switch(path[j], path[j + 1]);
+    }
+    delta
+}
+
+/// Cheapest open path over `addrs` found by nearest neighbour from every
+/// start, improved with 2-opt.
+fn shortest_path(cost: &ScanCost, addrs: &[u32]) -> Vec<u32> {
+    let mut best = addrs.to_vec();
+    let mut best_cost = path_cost(cost, &best);
+    for start in 0..addrs.len() {
+        let mut left = addrs.to_vec();
+        let mut path = vec![left.remove(start)];
+        while !left.is_empty() {
+            let last = *path.last().unwrap();
+            let (idx, _) = left
+                .iter()
+                .enumerate()
+                .min_by(|a, b| cost.switch(last, *a.1).total_cmp(&cost.switch(last, *b.1)))
+                .unwrap();
+            path.push(left.remove(idx));
+        }
+        let path_cost = path_cost(cost, &path);
+        if path_cost < best_cost {
+            best = path;
+            best_cost = path_cost;
+        }
+    }
+    loop {
+        let mut improved = false;
+        for i in 0..best.len() {
+            for j in i + 1..best.len() {
+                if reverse_delta(cost, &best, i, j) < -1e-6 {
+                    best[i..=j].reverse();
+                    improved = true;
+                }
+            }
+        }
+        if !improved {
+            return best;
+        }
+    }
+}
+
+/// Output order of the lines of one screen as indices into `lines`, see
+/// [`optimise`].
+pub fn order_lines(
+    lines: &[ScreenLine],
+    driver: &dyn LedDriverProfile,
+    cost: &ScanCost,
+) -> (Vec<usize>, OrderStats) {
+    let scan_addr = |addr: u32| match driver.scan_lines() {
+        Some(n) => addr % n.max(1),
+        None => addr,
+    };
+    let before: Vec<u32> = lines.iter().map(|l| scan_addr(l.addr)).collect();
+    let mut groups: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+    for (idx, line) in lines.iter().enumerate() {
+        groups.entry(scan_addr(line.addr)).or_default().push(idx);
+    }
+    let addrs: Vec<u32> = groups.keys().copied().collect();
+    let path = shortest_path(cost, &addrs);
+    let mut order = Vec::with_capacity(lines.len());
+    for addr in &path {
+        let mut group = groups.remove(addr).unwrap();
+        group.sort_by_key(|&idx| lines[idx].addr);
+        order.extend(group);
+    }
+    let stats = OrderStats {
+        lines: lines.len(),
+        steps: path.len(),
+        time_before_us: cost.time(&before),
+        time_after_us: cost.time(&path),
+    };
+    (order, stats)
+}
+
+/// Merge the lines of each scan group of `driver` into consecutive steps and
+/// order the steps of every screen and angle for the least switching time.
+/// `angle_map` is left in address order.
+pub fn optimise(
+    angle_map: &AngleMap,
+    driver: &dyn LedDriverProfile,
+    cost: &ScanCost,
+) -> (LineOrder, OrderStats) {
+    let mut stats = OrderStats::default();
+    let mut order = LineOrder::new();
+    for (&angle, lines_arr) in angle_map {
+        let orders = lines_arr.each_ref().map(|lines| {
+            let (order, line_stats) = order_lines(lines, driver, cost);
+            stats += line_stats;
+            order
+        });
+        order.insert(angle, orders);
+    }
+    (order, stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Rgb;
+    use crate::driver::{Mbi5264, PlainShiftRegister};
+    use crate::{Codec, W_PIXELS};
+
+    fn line(addr: u32) -> ScreenLine {
+        let mut pixels = [None; W_PIXELS];
+        pixels[addr as usize % W_PIXELS] = Some(Rgb::WHITE);
+        ScreenLine {
+            screen_idx: 0,
+            addr,
+            pixels,
+        }
+    }
+
+    #[test]
+    fn test_order_lines() {
+        let cost = ScanCost {
+            line_us: 2.0,
+            switch_us: 1.0,
+            metric: SwitchMetric::Bits,
+        };
+        assert_eq!(cost.time(&[7, 7, 8]), 2.0 + 2.0 + 4.0);
+        // address order flips four bits from 7 to 8
+        let lines = [1, 3, 7, 8, 9].map(line);
+        let (order, stats) = order_lines(&lines, &PlainShiftRegister, &cost);
+        let addrs: Vec<u32> = order.iter().map(|&idx| lines[idx].addr).collect();
+        assert_eq!(stats.steps, 5);
+        assert_eq!(stats.time_before_us, 10.0 + 1.0 + 1.0 + 4.0 + 1.0);
+        assert_eq!(stats.time_after_us, cost.time(&addrs));
+        assert!(stats.time_after_us < stats.time_before_us, "{addrs:?}");
+
+        // lines 3 and 19 share a scan group and merge into one step
+        let lines = [3, 4, 19].map(line);
+        let (order, stats) = order_lines(&lines, &Mbi5264, &cost);
+        assert_eq!(stats.steps, 2);
+        let addrs: Vec<u32> = order.iter().map(|&idx| lines[idx].addr).collect();
+        assert!(addrs == [3, 19, 4] || addrs == [4, 3, 19], "{addrs:?}");
+    }
+
+    #[test]
+    fn test_optimise() {
+        let codec = Codec::new();
+        let angle_map = codec.encode(&crate::pyramid_surface(), &Mbi5264);
+        for metric in [
+            SwitchMetric::Fixed,
+            SwitchMetric::Distance,
+            SwitchMetric::Bits,
+        ] {
+            let cost = ScanCost {
+                line_us: 3.4,
+                switch_us: 0.5,
+                metric,
+            };
+            let (order, stats) = optimise(&angle_map, &Mbi5264, &cost);
+            assert!(stats.time_after_us <= stats.time_before_us, "{metric:?}");
+            assert!(stats.steps <= stats.lines);
+            assert_eq!(order.len(), angle_map.len());
+            for (angle, orders) in &order {
+                for (order, lines) in orders.iter().zip(&angle_map[angle]) {
+                    // a permutation of the lines
+                    let mut sorted = order.clone();
+                    sorted.sort();
+                    assert_eq!(sorted, (0..lines.len()).collect::<Vec<_>>());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_reverse_delta() {
+        let cost = ScanCost {
+            line_us: 1.0,
+            switch_us: 1.0,
+            metric: SwitchMetric::Distance,
+        };
+        let path = [4, 9, 1, 7, 2, 12];
+        for i in 0..path.len() {
+            for j in i + 1..path.len() {
+                let mut reversed = path;
+                reversed[i..=j].reverse();
+                let full = path_cost(&cost, &reversed) - path_cost(&cost, &path);
+                assert_eq!(reverse_delta(&cost, &path, i, j), full, "{i}..={j}");
+            }
+        }
+    }
+}
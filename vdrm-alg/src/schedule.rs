@@ -0,0 +1,291 @@
+//! Time budget of the angle slots.
+//!
+//! An angle slot lasts the time the rotor takes to turn to the next slot, a
+//! `60 / (rpm * angles)` seconds for uniform slots. A screen has to scan all
+//! of its lines of an angle within that time, otherwise the next slot starts
+//! before it is done and the panel flickers. Screens are driven in parallel,
+//! so the busiest screen decides.
+
+use crate::driver::LedDriverProfile;
+use crate::geometry::{Geometry, LineTiming, ScanOrder, SlotsError};
+use crate::{AngleMap, NUM_FACETS, W_PIXELS};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleConfig {
+    pub rpm: f32,
+    /// Mirror facets of the rotor.
+    pub facets: u32,
+    /// Radians of the slot of every `AngleMap` key, indexed by key.
+    pub slot_widths: Vec<f32>,
+    /// Scan groups of the driver, lines of one group are lit together.
+    /// `None` if every line is scanned on its own.
+    pub scan_lines: Option<u32>,
+    /// Shift clock of the driver in Hz.
+    pub clock_hz: f32,
+    /// Clocks to shift one line.
+    pub pixels_per_line: u32,
+    /// Bit planes shifted per line, 1 for drivers doing the PWM themselves.
+    pub planes: u32,
+    /// Extra clocks per plane for latch and blanking.
+    pub latch_clocks: u32,
+}
+
+impl ScheduleConfig {
+    /// Defaults of the codec for a rotor of `geometry` at `rpm` driven by
+    /// `driver`.
+    pub fn new(rpm: f32, geometry: &Geometry, driver: &dyn LedDriverProfile) -> Self {
+        let slot_widths = (0..geometry.slots.len())
+            .map(|key| geometry.slots.width(geometry.key_angle(key)))
+            .collect();
+        Self {
+            rpm,
+            facets: NUM_FACETS as u32,
+            slot_widths,
+            scan_lines: driver.scan_lines(),
+            clock_hz: 20e6,
+            pixels_per_line: W_PIXELS as u32,
+            planes: 1,
+            latch_clocks: 4,
+        }
+    }
+
+    /// Time plan of the config, [`SlotsError::Empty`] without slot widths.
+    pub fn plan(&self) -> Result<Schedule, SlotsError> {
+        if self.slot_widths.is_empty() {
+            return Err(SlotsError::Empty);
+        }
+        let us_per_rad = 60e6 / (self.rpm * std::f32::consts::TAU);
+        let slots_us: Vec<f32> = self.slot_widths.iter().map(|w| w * us_per_rad).collect();
+        let slot_us = slots_us.iter().copied().fold(f32::INFINITY, f32::min);
+        let line_clocks = (self.pixels_per_line + self.latch_clocks) * self.planes;
+        let line_us = line_clocks as f32 * 1e6 / self.clock_hz;
+        Ok(Schedule {
+            slot_us,
+            line_us,
+            max_lines: (slot_us / line_us) as usize,
+            facet_us: 60e6 / (self.rpm * self.facets as f32),
+            slots_us,
+            scan_lines: self.scan_lines,
+        })
+    }
+}
+
+/// Durations following from a [`ScheduleConfig`], in microseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    /// Time of the shortest slot.
+    pub slot_us: f32,
+    /// Time to scan one line or scan group.
+    pub line_us: f32,
+    /// Lines or scan groups fitting in the shortest slot.
+    pub max_lines: usize,
+    /// Time one facet sweeps past the screens.
+    pub facet_us: f32,
+    /// Never empty.
+    slots_us: Vec<f32>,
+    scan_lines: Option<u32>,
+}
+
+/// An angle whose lines do not fit in its slot.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Overrun {
+    pub angle: u32,
+    pub screen_idx: usize,
+    /// Lines or scan groups of the busiest screen.
+    pub lines: usize,
+    pub time_us: f32,
+    /// Time of the angle's slot.
+    pub slot_us: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScheduleReport {
+    pub angles: usize,
+    /// Overrunning angles in key order.
+    pub overruns: Vec<Overrun>,
+    /// Highest scan time of an angle over its slot time.
+    pub peak_load: f32,
+}
+
+impl ScheduleReport {
+    pub fn fits(&self) -> bool {
+        self.overruns.is_empty()
+    }
+}
+
+impl Schedule {
+    /// Line timing of the schedule for `Geometry::timing`, lines start right
+    /// at the slot start.
+    pub fn line_timing(&self, rpm: f32) -> LineTiming {
+        LineTiming {
+            rpm,
+            latency_us: 0.0,
+            line_us: self.line_us,
+            order: match self.scan_lines {
+                Some(n) => ScanOrder::Grouped(n),
+                None => ScanOrder::Ascending,
+            },
+        }
+    }
+
+    /// Time of the slot of `AngleMap` key `key`.
+    pub fn key_slot_us(&self, key: u32) -> f32 {
+        self.slots_us[key as usize % self.slots_us.len()]
+    }
+
+    /// Lines or scan groups fitting in the slot of `key`.
+    pub fn key_max_lines(&self, key: u32) -> usize {
+        (self.key_slot_us(key) / self.line_us) as usize
+    }
+
+    /// Lines or scan groups a screen scans for `addrs`.
+    fn scans(&self, addrs: impl Iterator<Item = u32>) -> usize {
+        match self.scan_lines {
+            Some(n) => addrs.map(|a| a % n.max(1)).collect::<BTreeSet<_>>().len(),
+            None => addrs.collect::<BTreeSet<_>>().len(),
+        }
+    }
+
+    /// Check every angle of `angle_map` against the time of its own slot.
+    pub fn check(&self, angle_map: &AngleMap) -> ScheduleReport {
+        let mut report = ScheduleReport {
+            angles: angle_map.len(),
+            ..Default::default()
+        };
+        for (&angle, lines_arr) in angle_map {
+            let (screen_idx, lines) = lines_arr
+                .iter()
+                .map(|lines| self.scans(lines.iter().map(|l| l.addr)))
+                .enumerate()
+                .max_by_key(|&(idx, lines)| (lines, std::cmp::Reverse(idx)))
+                .unwrap();
+            let time_us = lines as f32 * self.line_us;
+            let slot_us = self.key_slot_us(angle);
+            report.peak_load = report.peak_load.max(time_us / slot_us);
+            if lines > self.key_max_lines(angle) {
+                report.overruns.push(Overrun {
+                    angle,
+                    screen_idx,
+                    lines,
+                    time_us,
+                    slot_us,
+                });
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Rgb;
+    use crate::driver::{Mbi5264, PlainShiftRegister};
+    use crate::{Codec, ScreenLine, H_PIXELS, TOTAL_ANGLES};
+
+    #[test]
+    fn test_schedule() {
+        let config = ScheduleConfig::new(1200.0, &Geometry::default(), &PlainShiftRegister);
+        let schedule = config.plan().unwrap();
+        // 1200 rpm with 512 slots, 68 clocks at 20 MHz per line
+        assert!((schedule.slot_us - 97.656).abs() < 1e-3);
+        assert!((schedule.line_us - 3.4).abs() < 1e-5);
+        assert_eq!(schedule.max_lines, 28);
+        assert!((schedule.facet_us - 6250.0).abs() < 1e-2);
+
+        let codec = Codec::new();
+        let mut surface = vec![];
+        for x in 0..W_PIXELS as u32 {
+            for y in 0..W_PIXELS as u32 {
+                surface.push((x, y, (H_PIXELS as u32 / 2, Rgb::WHITE)));
+            }
+        }
+        let angle_map = codec.encode(&surface, &PlainShiftRegister);
+        let report = schedule.check(&angle_map);
+        assert_eq!(report.angles, angle_map.len());
+        let busiest = angle_map
+            .values()
+            .flat_map(|lines_arr| lines_arr.iter().map(Vec::len))
+            .max()
+            .unwrap();
+        assert!((report.peak_load * schedule.slot_us - busiest as f32 * 3.4).abs() < 1e-2);
+        assert_eq!(report.fits(), busiest <= 28);
+
+        let slow = ScheduleConfig {
+            rpm: 300.0,
+            ..config.clone()
+        };
+        assert!(slow.plan().unwrap().check(&angle_map).fits());
+        let fast = ScheduleConfig {
+            rpm: 6000.0,
+            ..config.clone()
+        };
+        let report = fast.plan().unwrap().check(&angle_map);
+        assert!(!report.fits());
+        for overrun in &report.overruns {
+            let lines = &angle_map[&overrun.angle][overrun.screen_idx];
+            assert_eq!(overrun.lines, lines.len());
+            assert!(overrun.time_us > fast.plan().unwrap().slot_us);
+        }
+
+        // no slot for a key to wrap around to
+        let empty = ScheduleConfig {
+            slot_widths: vec![],
+            ..config.clone()
+        };
+        assert_eq!(empty.plan(), Err(SlotsError::Empty));
+
+        // scan groups are lit together
+        let grouped = ScheduleConfig::new(6000.0, &Geometry::default(), &Mbi5264)
+            .plan()
+            .unwrap();
+        assert!(grouped.check(&angle_map).overruns.len() <= report.overruns.len());
+        assert_eq!(grouped.line_timing(6000.0).order, ScanOrder::Grouped(16));
+    }
+
+    #[test]
+    fn test_schedule_slots() {
+        let mut geometry = Geometry::default();
+        geometry.slots = geometry.facing_slots(TOTAL_ANGLES as u32, 4.0);
+        geometry.angle_phase = 7;
+        let schedule = ScheduleConfig::new(1200.0, &geometry, &PlainShiftRegister)
+            .plan()
+            .unwrap();
+        let uniform = ScheduleConfig::new(1200.0, &Geometry::default(), &PlainShiftRegister)
+            .plan()
+            .unwrap();
+        let by_width = |key: &u32| schedule.key_slot_us(*key);
+        let keys = 0..TOTAL_ANGLES as u32;
+        let dense = keys
+            .clone()
+            .min_by(|a, b| by_width(a).total_cmp(&by_width(b)));
+        let wide = keys.max_by(|a, b| by_width(a).total_cmp(&by_width(b)));
+        let (dense, wide) = (dense.unwrap(), wide.unwrap());
+        assert_eq!(schedule.key_slot_us(dense), schedule.slot_us);
+        assert!(schedule.key_slot_us(wide) > 3.0 * schedule.slot_us);
+        assert!(schedule.slot_us < uniform.slot_us);
+        let slot = geometry.key_angle(dense);
+        let width_us = geometry.slots.width(slot) * 60e6 / (1200.0 * std::f32::consts::TAU);
+        assert!((schedule.slot_us - width_us).abs() < 1e-3);
+
+        // as many lines as fit a uniform slot overrun only the dense slot
+        let lines: Vec<_> = (0..uniform.max_lines as u32)
+            .map(|addr| ScreenLine {
+                screen_idx: 0,
+                addr,
+                pixels: [Some(Rgb::WHITE); W_PIXELS],
+            })
+            .collect();
+        let mut angle_map = AngleMap::new();
+        for key in [dense, wide] {
+            angle_map.entry(key).or_default()[0] = lines.clone();
+        }
+        assert!(uniform.check(&angle_map).fits());
+        let report = schedule.check(&angle_map);
+        assert_eq!(report.overruns.len(), 1);
+        assert_eq!(report.overruns[0].angle, dense);
+        assert_eq!(report.overruns[0].slot_us, schedule.slot_us);
+        assert!(report.peak_load > 1.0);
+    }
+}
@@ -0,0 +1,405 @@
+//! Least-squares fit of a `Geometry` to measured reference points.
+//!
+//! Each [`Observation`] says that a panel pixel lit at an encoder angle was
+//! seen at a point in the display space, e.g. by a camera or a probe. The
+//! forward model is the codec's own: `mirror_mat4` with the facet errors
+//! applied to the pixel's position on its `Screen`. Levenberg-Marquardt with
+//! a numeric Jacobian fits a rigid motion per screen, a mirror offset and
+//! angle phase shared by all facets, and the tilt of every observed facet
+//! with its azimuth and offset relative to the shared ones. Facets without
+//! observations get the shared offset and phase.
+//!
+//! `MIRROR_OFFSET` is a constant of the codec and `Geometry::angle_phase`
+//! counts whole angle slots, so the fitted mirror offset and the remaining
+//! phase are written as a common part of the facet offsets and azimuths, and
+//! reported in [`Solution`].
+//!
+//! Save `Solution::geometry` as the hardware profile with `Geometry::save`
+//! (feature `serde`), load it with `Geometry::load` and build the codec from
+//! it with `Codec::with_geometry`.
+
+use crate::geometry::Geometry;
+use crate::{cacl_view_point, line_mat4, Screen, NUM_SCREENS};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Observation {
+    pub screen_idx: usize,
+    /// Panel line and pixel, before `Geometry::mounts` is undone.
+    pub addr: u32,
+    pub pixel: u32,
+    /// `AngleMap` key, i.e. the encoder angle.
+    pub angle: u32,
+    pub point: (f32, f32, f32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Solution {
+    /// Fitted geometry, see the module docs for saving it.
+    pub geometry: Geometry,
+    pub iterations: usize,
+    /// Observations left out of the fit because `predict` has no point for
+    /// them, e.g. pixels off the panel or bad screen indices.
+    pub dropped: usize,
+    /// Root mean square distance between prediction and observation.
+    pub rms_before: f32,
+    pub rms_after: f32,
+    /// Mirror offset change fitted for all facets, added to the offset of
+    /// every facet of the initial geometry.
+    pub mirror_offset: f32,
+    /// Angle phase change in radians fitted for all facets, added to the
+    /// azimuth of every facet of the initial geometry.
+    pub angle_phase: f32,
+}
+
+/// Where `geometry` puts an observed pixel, `None` if the pixel is off the
+/// panel after undoing the mount or the screen index is out of range.
+pub fn predict(geometry: &Geometry, obs: &Observation) -> Option<(f32, f32, f32)> {
+    let mount = geometry.mounts.get(obs.screen_idx)?;
+    let (addr, pixel) = mount.from_panel(obs.addr, obs.pixel)?;
+    let angle = geometry.key_angle(obs.angle);
+    let mat = line_mat4(geometry, angle, obs.addr);
+    let screen = &geometry.screens[obs.screen_idx];
+    Some(cacl_view_point(mat, screen, addr, pixel).0)
+}
+
+const SCREEN_PARAMS: usize = 6;
+/// Mirror offset and angle phase, shared by every facet.
+const GLOBAL_PARAMS: usize = 2;
+
+/// Screen moved by translation `t` and rotation `r` (xyz Euler angles)
+/// around its centre.
+fn move_screen(screen: &Screen, t: [f64; 3], r: [f64; 3]) -> Screen {
+    let points = screen.points.map(glam::Vec3::from);
+    let centre = points.iter().sum::<glam::Vec3>() / 4.;
+    let rot = glam::Mat3::from_euler(glam::EulerRot::XYZ, r[0] as f32, r[1] as f32, r[2] as f32);
+    let t = glam::Vec3::new(t[0] as f32, t[1] as f32, t[2] as f32);
+    Screen {
+        points: points.map(|p| (centre + rot * (p - centre) + t).into()),
+    }
+}
+
+/// Parameters are changes to `initial`: a rigid motion per screen, the
+/// mirror offset and angle phase of all facets, the tilt of every observed
+/// facet, and the azimuth and offset of the observed facets relative to the
+/// shared ones. The relative parts sum to zero, the last facet's are the
+/// negated sum of the others.
+struct Model<'a> {
+    initial: &'a Geometry,
+    facets: Vec<usize>,
+}
+
+impl Model<'_> {
+    fn params_len(&self) -> usize {
+        let relative = self.facets.len().saturating_sub(1);
+        NUM_SCREENS * SCREEN_PARAMS + GLOBAL_PARAMS + self.facets.len() + 2 * relative
+    }
+
+    /// Common mirror offset and angle phase of `params`.
+    fn shared(&self, params: &[f64]) -> (f32, f32) {
+        let global = &params[NUM_SCREENS * SCREEN_PARAMS..];
+        (global[0] as f32, global[1] as f32)
+    }
+
+    fn geometry(&self, params: &[f64]) -> Geometry {
+        let mut geometry = self.initial.clone();
+        for (screen, p) in geometry
+            .screens
+            .iter_mut()
+            .zip(params.chunks_exact(SCREEN_PARAMS))
+        {
+            *screen = move_screen(screen, [p[0], p[1], p[2]], [p[3], p[4], p[5]]);
+        }
+        let (offset, phase) = self.shared(params);
+        for facet in geometry.facets.iter_mut() {
+            facet.offset += offset;
+            facet.azimuth += phase;
+        }
+        let count = self.facets.len();
+        let (tilts, rest) = params[NUM_SCREENS * SCREEN_PARAMS + GLOBAL_PARAMS..].split_at(count);
+        let (azimuths, offsets) = rest.split_at(count.saturating_sub(1));
+        let relative = |values: &[f64], i: usize| match values.get(i) {
+            Some(v) => *v as f32,
+            None => -values.iter().sum::<f64>() as f32,
+        };
+        for (i, &idx) in self.facets.iter().enumerate() {
+            let facet = &mut geometry.facets[idx];
+            facet.tilt += tilts[i] as f32;
+            facet.azimuth += relative(azimuths, i);
+            facet.offset += relative(offsets, i);
+        }
+        geometry
+    }
+
+    fn residuals(&self, params: &[f64], observations: &[Observation]) -> Vec<f64> {
+        let geometry = self.geometry(params);
+        let mut residuals = Vec::with_capacity(observations.len() * 3);
+        for obs in observations {
+            // the fit changes neither mounts nor screen indices, `solve`
+            // dropped the observations without a prediction
+            let (x, y, z) = predict(&geometry, obs).unwrap();
+            residuals.push((x - obs.point.0) as f64);
+            residuals.push((y - obs.point.1) as f64);
+            residuals.push((z - obs.point.2) as f64);
+        }
+        residuals
+    }
+}
+
+fn rms(residuals: &[f64]) -> f32 {
+    let points = (residuals.len() / 3).max(1);
+    (residuals.iter().map(|r| r * r).sum::<f64>() / points as f64).sqrt() as f32
+}
+
+/// Solve `a x = b` for a symmetric positive definite `a` by Cholesky.
+fn solve_spd(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for j in 0..n {
+        let d = a[j][j] - (0..j).map(|k| a[j][k] * a[j][k]).sum::<f64>();
+        if d <= 0. {
+            return None;
+        }
+        a[j][j] = d.sqrt();
+        for i in j + 1..n {
+            let s = a[i][j] - (0..j).map(|k| a[i][k] * a[j][k]).sum::<f64>();
+            a[i][j] = s / a[j][j];
+        }
+    }
+    for i in 0..n {
+        b[i] = (b[i] - (0..i).map(|k| a[i][k] * b[k]).sum::<f64>()) / a[i][i];
+    }
+    for i in (0..n).rev() {
+        b[i] = (b[i] - (i + 1..n).map(|k| a[k][i] * b[k]).sum::<f64>()) / a[i][i];
+    }
+    Some(b)
+}
+
+/// Fit `initial` to `observations`, at most `max_iterations` steps.
+/// Observations `predict` has no point for are left out.
+pub fn solve(initial: &Geometry, observations: &[Observation], max_iterations: usize) -> Solution {
+    let kept: Vec<Observation> = observations
+        .iter()
+        .filter(|obs| predict(initial, obs).is_some())
+        .copied()
+        .collect();
+    let dropped = observations.len() - kept.len();
+    let observations = &kept[..];
+    let mut facets: Vec<usize> = observations
+        .iter()
+        .map(|o| initial.slots.facet(initial.key_angle(o.angle)))
+        .collect();
+    facets.sort_unstable();
+    facets.dedup();
+    let model = Model { initial, facets };
+    let mut params = vec![0.; model.params_len()];
+    let mut residuals = model.residuals(&params, observations);
+    let rms_before = rms(&residuals);
+    let mut cost: f64 = residuals.iter().map(|r| r * r).sum();
+    let mut lambda = 1e-3;
+    let mut iterations = 0;
+    let step = 1e-3;
+    while iterations < max_iterations {
+        iterations += 1;
+        // central differences, one column per parameter
+        let jacobian: Vec<Vec<f64>> = (0..params.len())
+            .map(|k| {
+                let mut plus = params.clone();
+                plus[k] += step;
+                let mut minus = params.clone();
+                minus[k] -= step;
+                let r_plus = model.residuals(&plus, observations);
+                let r_minus = model.residuals(&minus, observations);
+                r_plus
+                    .iter()
+                    .zip(r_minus)
+                    .map(|(p, m)| (p - m) / (2. * step))
+                    .collect()
+            })
+            .collect();
+        let n = params.len();
+        let mut jtj = vec![vec![0.; n]; n];
+        for i in 0..n {
+            for j in 0..=i {
+                let v: f64 = jacobian[i]
+                    .iter()
+                    .zip(&jacobian[j])
+                    .map(|(a, b)| a * b)
+                    .sum();
+                jtj[i][j] = v;
+                jtj[j][i] = v;
+            }
+        }
+        let jtr: Vec<f64> = jacobian
+            .iter()
+            .map(|col| -col.iter().zip(&residuals).map(|(a, r)| a * r).sum::<f64>())
+            .collect();
+        let mut improved = false;
+        while lambda < 1e6 {
+            let mut a = jtj.clone();
+            for (i, row) in a.iter_mut().enumerate() {
+                row[i] += lambda * (1. + jtj[i][i]);
+            }
+            let Some(delta) = solve_spd(a, jtr.clone()) else {
+                lambda *= 10.;
+                continue;
+            };
+            let candidate: Vec<f64> = params.iter().zip(&delta).map(|(p, d)| p + d).collect();
+            let candidate_residuals = model.residuals(&candidate, observations);
+            let candidate_cost: f64 = candidate_residuals.iter().map(|r| r * r).sum();
+            if candidate_cost < cost {
+                improved = (cost - candidate_cost) > cost * 1e-9;
+                params = candidate;
+                residuals = candidate_residuals;
+                cost = candidate_cost;
+                lambda = (lambda / 10.).max(1e-9);
+                break;
+            }
+            lambda *= 10.;
+        }
+        if !improved {
+            break;
+        }
+    }
+    let (mirror_offset, angle_phase) = model.shared(&params);
+    Solution {
+        geometry: model.geometry(&params),
+        mirror_offset,
+        angle_phase,
+        iterations,
+        dropped,
+        rms_before,
+        rms_after: rms(&residuals),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::geometry::FacetError;
+    use crate::{NUM_FACETS, TOTAL_ANGLES, W_PIXELS};
+
+    /// xorshift, the tests don't need more
+    fn noise(state: &mut u32, amplitude: f32) -> f32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        (*state as f32 / u32::MAX as f32 - 0.5) * 2. * amplitude
+    }
+
+    #[test]
+    fn test_solve_synthetic() {
+        let nominal = Geometry::default();
+        let mut truth = nominal.clone();
+        truth.screens[0] = move_screen(&truth.screens[0], [0.01, -0.02, 0.005], [0.01, 0., 0.]);
+        truth.screens[2] = move_screen(&truth.screens[2], [-0.01, 0., 0.02], [0., 0.005, -0.01]);
+        for (idx, facet) in truth.facets.iter_mut().enumerate() {
+            *facet = FacetError {
+                tilt: 0.004 * (idx as f32 - 3.5),
+                azimuth: 0.015 + 0.001 * (idx % 2) as f32,
+                offset: 0.02 - 0.001 * (idx % 2) as f32,
+            };
+        }
+        let mut state = 0x1234_5678;
+        let amplitude = 0.002;
+        let per_facet = (TOTAL_ANGLES / NUM_FACETS) as u32;
+        let mut observations = vec![];
+        for screen_idx in 0..NUM_SCREENS {
+            for angle in (per_facet * 3 / 2..per_facet * 3).step_by(7) {
+                for (addr, pixel) in [(4, 4), (4, 60), (60, 4), (60, 60), (32, 20)] {
+                    let mut obs = Observation {
+                        screen_idx,
+                        addr,
+                        pixel: pixel % W_PIXELS as u32,
+                        angle,
+                        point: (0., 0., 0.),
+                    };
+                    let (x, y, z) = predict(&truth, &obs).unwrap();
+                    obs.point = (
+                        x + noise(&mut state, amplitude),
+                        y + noise(&mut state, amplitude),
+                        z + noise(&mut state, amplitude),
+                    );
+                    observations.push(obs);
+                }
+            }
+        }
+        let (fit, check): (Vec<_>, Vec<_>) = observations
+            .iter()
+            .enumerate()
+            .partition(|(idx, _)| idx % 4 != 0);
+        let fit: Vec<Observation> = fit.into_iter().map(|(_, o)| *o).collect();
+        let check: Vec<Observation> = check.into_iter().map(|(_, o)| *o).collect();
+
+        // off the panel and of no screen, both far from any prediction
+        let mut with_bad = fit.clone();
+        let far = (10., 10., 10.);
+        with_bad.push(Observation {
+            addr: W_PIXELS as u32,
+            point: far,
+            ..fit[0]
+        });
+        with_bad.push(Observation {
+            screen_idx: NUM_SCREENS,
+            point: far,
+            ..fit[0]
+        });
+        assert_eq!(predict(&nominal, &with_bad[fit.len() + 1]), None);
+
+        let solution = solve(&nominal, &with_bad, 50);
+        assert_eq!(solution.dropped, 2);
+        let clean = solve(&nominal, &fit, 50);
+        assert_eq!(clean.dropped, 0);
+        assert_eq!(
+            Solution {
+                dropped: 2,
+                ..clean
+            },
+            solution
+        );
+        assert!(solution.rms_before > 0.02, "{}", solution.rms_before);
+        // uniform noise in three axes has rms amplitude
+        assert!(
+            solution.rms_after < 1.5 * amplitude,
+            "{}",
+            solution.rms_after
+        );
+        let check_rms = check
+            .iter()
+            .map(|obs| {
+                let p = glam::Vec3::from(predict(&solution.geometry, obs).unwrap());
+                (p - glam::Vec3::from(obs.point)).length_squared()
+            })
+            .sum::<f32>()
+            / check.len() as f32;
+        assert!(check_rms.sqrt() < 1.5 * amplitude, "{}", check_rms.sqrt());
+        // only facets 2 and 3 were seen, the others get the shared part
+        let unseen = solution.geometry.facets[6];
+        assert_eq!(unseen.tilt, nominal.facets[6].tilt);
+        assert_eq!(
+            unseen.offset,
+            nominal.facets[6].offset + solution.mirror_offset
+        );
+        assert_eq!(
+            unseen.azimuth,
+            nominal.facets[6].azimuth + solution.angle_phase
+        );
+        assert!(
+            (solution.mirror_offset - 0.0195).abs() < 0.001,
+            "{solution:?}"
+        );
+        assert!(
+            (solution.angle_phase - 0.0155).abs() < 0.001,
+            "{solution:?}"
+        );
+        assert!(solution.iterations > 1);
+
+        #[cfg(feature = "serde")]
+        {
+            let path =
+                std::env::temp_dir().join(format!("vdrm-profile-{}.json", std::process::id()));
+            solution.geometry.save(&path).unwrap();
+            let loaded = Geometry::load(&path);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.unwrap(), solution.geometry);
+        }
+    }
+}
@@ -0,0 +1,488 @@
+//! Sparse form of `ScreenLine`: a bitmask of the lit pixels and their colors
+//! as runs of equal neighbours. Most pixels of a line are dark, so this is a
+//! fraction of the dense `[Option<PixelColor>; W_PIXELS]`.
+//!
+//! With the `serde` feature a `SparseLine` is written as its screen index,
+//! address, the mask as `u32` words, low word first, which JavaScript numbers
+//! hold exactly, and the runs as `[length, 0xRRGGBB]` pairs. `ScreenLine`,
+//! and so `AngleMap`, is serialized in the same form.
+//!
+//! [`to_bytes`] is the transmission format. All integers are little-endian.
+//!
+//! | size             | field                                        |
+//! |------------------|----------------------------------------------|
+//! | 4                | number of lines                              |
+//! | ...              | lines                                        |
+//! | 4                | CRC-32 (IEEE) of every preceding byte        |
+//!
+//! A line is the angle (`u32`), the screen index (`u8`), the address
+//! (`u32`), the mask as `MASK_WORDS` `u64`s, the run count (`u16`) and the
+//! runs, each a length (`u16`) and `[r, g, b]`.
+
+use crate::frame::crc32;
+use crate::{AngleMap, PixelColor, ScreenLine, NUM_SCREENS, W_PIXELS};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+pub const MASK_WORDS: usize = W_PIXELS.div_ceil(64);
+const LINE_HEADER_LEN: usize = 9 + MASK_WORDS * 8 + 2;
+const RUN_LEN: usize = 5;
+
+pub type Mask = [u64; MASK_WORDS];
+
+/// `len` consecutive lit pixels of one color.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ColorRun {
+    pub len: u16,
+    pub color: PixelColor,
+}
+
+/// Mask and color runs of dense pixels.
+pub fn compress(pixels: &[Option<PixelColor>; W_PIXELS]) -> (Mask, Vec<ColorRun>) {
+    let mut mask = [0; MASK_WORDS];
+    let mut runs: Vec<ColorRun> = vec![];
+    for (idx, color) in pixels.iter().enumerate() {
+        let Some(color) = *color else {
+            continue;
+        };
+        mask[idx / 64] |= 1 << (idx % 64);
+        match runs.last_mut() {
+            Some(run) if run.color == color => run.len += 1,
+            _ => runs.push(ColorRun { len: 1, color }),
+        }
+    }
+    (mask, runs)
+}
+
+/// Dense pixels of a mask and its runs, `None` if the runs do not cover the
+/// lit pixels exactly.
+pub fn decompress(mask: &Mask, runs: &[ColorRun]) -> Option<[Option<PixelColor>; W_PIXELS]> {
+    let mut pixels = [None; W_PIXELS];
+    let mut colors = runs
+        .iter()
+        .flat_map(|run| std::iter::repeat_n(run.color, run.len as usize));
+    for (word_idx, &word) in mask.iter().enumerate() {
+        let mut word = word;
+        while word != 0 {
+            let idx = word_idx * 64 + word.trailing_zeros() as usize;
+            *pixels.get_mut(idx)? = Some(colors.next()?);
+            word &= word - 1;
+        }
+    }
+    match colors.next() {
+        Some(_) => None,
+        None => Some(pixels),
+    }
+}
+
+/// A `ScreenLine` keeping only its lit pixels.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "SerdeLine", try_from = "SerdeLine")
+)]
+pub struct SparseLine {
+    pub screen_idx: usize,
+    pub addr: u32,
+    mask: Mask,
+    runs: Vec<ColorRun>,
+}
+
+impl SparseLine {
+    /// `None` if `runs` do not cover the lit pixels of `mask` exactly.
+    pub fn new(screen_idx: usize, addr: u32, mask: Mask, runs: Vec<ColorRun>) -> Option<Self> {
+        decompress(&mask, &runs)?;
+        Some(Self {
+            screen_idx,
+            addr,
+            mask,
+            runs,
+        })
+    }
+
+    /// Bit `i % 64` of word `i / 64` is set if pixel `i` is lit.
+    pub fn mask(&self) -> &Mask {
+        &self.mask
+    }
+
+    /// Colors of the lit pixels in pixel order.
+    pub fn runs(&self) -> &[ColorRun] {
+        &self.runs
+    }
+
+    pub fn lit(&self) -> usize {
+        self.mask.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Bytes the line takes in memory.
+    pub fn mem_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.runs.len() * std::mem::size_of::<ColorRun>()
+    }
+}
+
+/// Serde form of [`SparseLine`], see the module docs.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerdeLine {
+    screen_idx: usize,
+    addr: u32,
+    mask: [u32; MASK_WORDS * 2],
+    runs: Vec<(u16, u32)>,
+}
+
+#[cfg(feature = "serde")]
+impl From<SparseLine> for SerdeLine {
+    fn from(line: SparseLine) -> Self {
+        let mut mask = [0; MASK_WORDS * 2];
+        for (idx, word) in line.mask.iter().enumerate() {
+            mask[idx * 2] = *word as u32;
+            mask[idx * 2 + 1] = (word >> 32) as u32;
+        }
+        let runs = line
+            .runs
+            .iter()
+            .map(|run| (run.len, run.color.to_rgb24()))
+            .collect();
+        Self {
+            screen_idx: line.screen_idx,
+            addr: line.addr,
+            mask,
+            runs,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<SerdeLine> for SparseLine {
+    type Error = &'static str;
+
+    fn try_from(line: SerdeLine) -> Result<Self, Self::Error> {
+        if line.screen_idx >= NUM_SCREENS {
+            return Err("screen index out of range");
+        }
+        let mut mask = [0; MASK_WORDS];
+        for (idx, word) in mask.iter_mut().enumerate() {
+            *word = line.mask[idx * 2] as u64 | (line.mask[idx * 2 + 1] as u64) << 32;
+        }
+        let runs = line
+            .runs
+            .into_iter()
+            .map(|(len, color)| ColorRun {
+                len,
+                color: PixelColor::from_rgb24(color),
+            })
+            .collect();
+        Self::new(line.screen_idx, line.addr, mask, runs).ok_or("pixel runs do not match the mask")
+    }
+}
+
+impl From<&ScreenLine> for SparseLine {
+    fn from(line: &ScreenLine) -> Self {
+        let (mask, runs) = compress(&line.pixels);
+        Self {
+            screen_idx: line.screen_idx,
+            addr: line.addr,
+            mask,
+            runs,
+        }
+    }
+}
+
+impl From<ScreenLine> for SparseLine {
+    fn from(line: ScreenLine) -> Self {
+        (&line).into()
+    }
+}
+
+impl From<&SparseLine> for ScreenLine {
+    fn from(line: &SparseLine) -> Self {
+        ScreenLine {
+            screen_idx: line.screen_idx,
+            addr: line.addr,
+            pixels: decompress(&line.mask, &line.runs).unwrap(),
+        }
+    }
+}
+
+impl From<SparseLine> for ScreenLine {
+    fn from(line: SparseLine) -> Self {
+        (&line).into()
+    }
+}
+
+pub type SparseAngleMap = BTreeMap<u32, [Vec<SparseLine>; NUM_SCREENS]>;
+
+pub fn sparse(angle_map: &AngleMap) -> SparseAngleMap {
+    angle_map
+        .iter()
+        .map(|(&angle, lines_arr)| {
+            let lines = lines_arr
+                .each_ref()
+                .map(|lines| lines.iter().map(Into::into).collect());
+            (angle, lines)
+        })
+        .collect()
+}
+
+pub fn dense(angle_map: &SparseAngleMap) -> AngleMap {
+    angle_map
+        .iter()
+        .map(|(&angle, lines_arr)| {
+            let lines = lines_arr
+                .each_ref()
+                .map(|lines| lines.iter().map(Into::into).collect());
+            (angle, lines)
+        })
+        .collect()
+}
+
+/// Bytes of the angles and lines of `angle_map`, not counting map nodes and
+/// spare capacity.
+pub fn dense_bytes(angle_map: &AngleMap) -> usize {
+    let lines: usize = angle_map.values().flatten().map(Vec::len).sum();
+    angle_map.len() * std::mem::size_of::<(u32, [Vec<ScreenLine>; NUM_SCREENS])>()
+        + lines * std::mem::size_of::<ScreenLine>()
+}
+
+/// [`dense_bytes`] of the sparse form.
+pub fn sparse_bytes(angle_map: &SparseAngleMap) -> usize {
+    let lines: usize = angle_map
+        .values()
+        .flatten()
+        .flatten()
+        .map(SparseLine::mem_bytes)
+        .sum();
+    angle_map.len() * std::mem::size_of::<(u32, [Vec<SparseLine>; NUM_SCREENS])>() + lines
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SparseError {
+    Truncated,
+    BadScreen(u8),
+    /// Runs of the line at `offset` do not match its mask.
+    BadRuns(usize),
+    Crc {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl Display for SparseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SparseError::Truncated => write!(f, "sparse lines truncated"),
+            SparseError::BadScreen(idx) => write!(f, "screen index {idx} out of range"),
+            SparseError::BadRuns(offset) => {
+                write!(f, "runs of the line at {offset} do not match its mask")
+            }
+            SparseError::Crc { expected, actual } => {
+                write!(
+                    f,
+                    "crc mismatch expected {expected:08x} actual {actual:08x}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SparseError {}
+
+pub fn to_bytes(angle_map: &SparseAngleMap) -> Vec<u8> {
+    let lines: usize = angle_map.values().flatten().map(Vec::len).sum();
+    let mut buf = vec![];
+    buf.extend((lines as u32).to_le_bytes());
+    for (angle, lines_arr) in angle_map {
+        for line in lines_arr.iter().flatten() {
+            buf.extend(angle.to_le_bytes());
+            buf.push(line.screen_idx as u8);
+            buf.extend(line.addr.to_le_bytes());
+            for word in line.mask {
+                buf.extend(word.to_le_bytes());
+            }
+            buf.extend((line.runs.len() as u16).to_le_bytes());
+            for run in &line.runs {
+                buf.extend(run.len.to_le_bytes());
+                buf.extend(run.color.channels());
+            }
+        }
+    }
+    let crc = crc32(&buf);
+    buf.extend(crc.to_le_bytes());
+    buf
+}
+
+pub fn from_bytes(buf: &[u8]) -> Result<SparseAngleMap, SparseError> {
+    if buf.len() < 8 {
+        return Err(SparseError::Truncated);
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    let expected = u32::from_le_bytes(crc.try_into().unwrap());
+    let actual = crc32(body);
+    if expected != actual {
+        return Err(SparseError::Crc { expected, actual });
+    }
+    let u16_at = |offset: usize| u16::from_le_bytes([body[offset], body[offset + 1]]);
+    let u32_at = |offset: usize| u32::from_le_bytes(body[offset..offset + 4].try_into().unwrap());
+    let count = u32_at(0);
+    let mut angle_map = SparseAngleMap::new();
+    let mut offset = 4;
+    for _ in 0..count {
+        if body.len() < offset + LINE_HEADER_LEN {
+            return Err(SparseError::Truncated);
+        }
+        let angle = u32_at(offset);
+        let screen_idx = body[offset + 4];
+        if screen_idx as usize >= NUM_SCREENS {
+            return Err(SparseError::BadScreen(screen_idx));
+        }
+        let addr = u32_at(offset + 5);
+        let mut mask = [0; MASK_WORDS];
+        for (idx, word) in mask.iter_mut().enumerate() {
+            let start = offset + 9 + idx * 8;
+            *word = u64::from_le_bytes(body[start..start + 8].try_into().unwrap());
+        }
+        let run_count = u16_at(offset + LINE_HEADER_LEN - 2) as usize;
+        let runs_start = offset + LINE_HEADER_LEN;
+        if body.len() < runs_start + run_count * RUN_LEN {
+            return Err(SparseError::Truncated);
+        }
+        let runs = body[runs_start..runs_start + run_count * RUN_LEN]
+            .chunks_exact(RUN_LEN)
+            .map(|run| ColorRun {
+                len: u16::from_le_bytes([run[0], run[1]]),
+                color: PixelColor::new(run[2], run[3], run[4]),
+            })
+            .collect();
+        let line = SparseLine::new(screen_idx as usize, addr, mask, runs)
+            .ok_or(SparseError::BadRuns(offset))?;
+        angle_map.entry(angle).or_default()[screen_idx as usize].push(line);
+        offset = runs_start + run_count * RUN_LEN;
+    }
+    if offset != body.len() {
+        return Err(SparseError::Truncated);
+    }
+    Ok(angle_map)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Rgb;
+    use crate::driver::PlainShiftRegister;
+    use crate::Codec;
+
+    #[test]
+    fn test_compress() {
+        let red = Rgb::new(0xff, 0, 0);
+        let mut pixels = [None; W_PIXELS];
+        for idx in [3, 4, 5, 9] {
+            pixels[idx] = Some(red);
+        }
+        pixels[W_PIXELS - 1] = Some(Rgb::WHITE);
+        let (mask, runs) = compress(&pixels);
+        assert_eq!(mask[0], 0b10_0011_1000 | 1 << 63);
+        assert_eq!(
+            runs,
+            [
+                ColorRun { len: 4, color: red },
+                ColorRun {
+                    len: 1,
+                    color: Rgb::WHITE
+                }
+            ]
+        );
+        assert_eq!(decompress(&mask, &runs), Some(pixels));
+        assert_eq!(decompress(&mask, &runs[..1]), None);
+        assert!(SparseLine::new(0, 0, [0; MASK_WORDS], runs).is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut pixels = [None; W_PIXELS];
+        pixels[0] = Some(Rgb::new(0xff, 0, 0xff));
+        pixels[W_PIXELS - 1] = Some(Rgb::new(0, 0, 7));
+        let line = SparseLine::from(&ScreenLine {
+            screen_idx: 1,
+            addr: 12,
+            pixels,
+        });
+        let json = serde_json::to_string(&line).unwrap();
+        // bit 63 does not fit a JavaScript number, the high word does
+        assert_eq!(
+            json,
+            r#"{"screen_idx":1,"addr":12,"mask":[1,2147483648],"runs":[[1,16711935],[1,7]]}"#
+        );
+        assert_eq!(serde_json::from_str::<SparseLine>(&json).unwrap(), line);
+        let bad = json.replace("[1,7]", "[2,7]");
+        assert!(serde_json::from_str::<SparseLine>(&bad).is_err());
+        let bad = json.replace(r#""screen_idx":1"#, r#""screen_idx":3"#);
+        assert!(serde_json::from_str::<SparseLine>(&bad).is_err());
+
+        let mut sparse_map = SparseAngleMap::new();
+        sparse_map.insert(100, [vec![], vec![line.clone()], vec![]]);
+        let bytes = bincode::serialize(&sparse_map).unwrap();
+        let decoded: SparseAngleMap = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded, sparse_map);
+
+        // dense lines are written in the same form
+        let angle_map = dense(&sparse_map);
+        assert_eq!(
+            serde_json::to_string(&ScreenLine::from(&line)).unwrap(),
+            json
+        );
+        let angle_json = serde_json::to_string(&angle_map).unwrap();
+        assert_eq!(angle_json, serde_json::to_string(&sparse_map).unwrap());
+        assert_eq!(
+            serde_json::from_str::<AngleMap>(&angle_json).unwrap(),
+            angle_map
+        );
+        assert!(serde_json::from_str::<ScreenLine>(&bad).is_err());
+        let bytes = bincode::serialize(&angle_map).unwrap();
+        assert_eq!(bincode::deserialize::<AngleMap>(&bytes).unwrap(), angle_map);
+
+        let geometry = crate::geometry::Geometry::with_rotate(0.1, Some(0.2));
+        let json = serde_json::to_string(&geometry).unwrap();
+        assert_eq!(
+            serde_json::from_str::<crate::geometry::Geometry>(&json).unwrap(),
+            geometry
+        );
+        let bytes = bincode::serialize(&geometry).unwrap();
+        assert_eq!(
+            bincode::deserialize::<crate::geometry::Geometry>(&bytes).unwrap(),
+            geometry
+        );
+    }
+
+    #[test]
+    fn test_sparse_pyramid() {
+        let angle_map = Codec::new().encode(&crate::pyramid_surface(), &PlainShiftRegister);
+        let sparse_map = sparse(&angle_map);
+        assert_eq!(dense(&sparse_map), angle_map);
+        let (dense_mem, sparse_mem) = (dense_bytes(&angle_map), sparse_bytes(&sparse_map));
+        assert!(sparse_mem * 4 < dense_mem);
+        let bytes = to_bytes(&sparse_map);
+        assert_eq!(bytes.len(), 122663);
+        assert!(bytes.len() * 10 < dense_mem);
+        let lit: usize = sparse_map
+            .values()
+            .flatten()
+            .flatten()
+            .map(SparseLine::lit)
+            .sum();
+        assert_eq!(lit, 7345);
+        #[cfg(feature = "serde")]
+        {
+            let serialized = bincode::serialize(&angle_map).unwrap();
+            assert_eq!(serialized, bincode::serialize(&sparse_map).unwrap());
+            assert!(serialized.len() * 5 < dense_mem);
+        }
+
+        assert_eq!(from_bytes(&bytes).unwrap(), sparse_map);
+        let mut bad = bytes.clone();
+        bad[6] ^= 1;
+        assert!(matches!(from_bytes(&bad), Err(SparseError::Crc { .. })));
+        assert_eq!(from_bytes(&bytes[..6]), Err(SparseError::Truncated));
+    }
+}
@@ -14,12 +14,11 @@ fn mirror_mat3(angle_f: f32) -> glam::Mat3A {
     let sin2 = sin * sin;
     let cos2 = cos * cos;
     let sin_cos = sin * cos;
-    let mat_mir = glam::Mat3A::from_cols(
+    glam::Mat3A::from_cols(
         glam::Vec3A::new(sin2 - cos2, -2.0 * sin_cos, 0.),
         glam::Vec3A::new(-2.0 * sin_cos, cos2 - sin2, 0.),
         glam::Vec3A::new(2.0 * MIRROR_OFFSET * cos, 2.0 * MIRROR_OFFSET * sin, 1.),
-    );
-    mat_mir
+    )
 }
 
 fn mirror_points_f(angle_f: f32, points: &[(f32, f32)]) -> Vec<(f32, f32)> {
@@ -59,7 +58,7 @@ pub fn draw(
     canvas: HtmlCanvasElement,
     angle_offset: u32,
     enb_screens: Vec<usize>,
-) -> DrawResult<impl Fn((i32, i32)) -> Option<(f32, f32)>> {
+) -> DrawResult<()> {
     let backend = CanvasBackend::with_canvas_object(canvas).unwrap();
     let root = backend.into_drawing_area();
     let font: FontDesc = ("sans-serif", 20.0).into();
@@ -69,7 +68,7 @@ pub fn draw(
 
     let mut chart = ChartBuilder::on(&root)
         .margin(20u32)
-        .caption(format!("2d simulate"), font)
+        .caption("2d simulate", font)
         .x_label_area_size(30u32)
         .y_label_area_size(30u32)
         .build_cartesian_2d(-cord_len..cord_len, -cord_len..cord_len)?;
@@ -104,5 +103,5 @@ pub fn draw(
     }
 
     root.present()?;
-    return Ok(chart.into_coord_trans());
+    Ok(())
 }
@@ -3,39 +3,13 @@ use plotters::coord::ranged3d::ProjectionMatrix;
 use plotters::prelude::*;
 use plotters_canvas::CanvasBackend;
 use std::collections::BTreeMap;
+use vdrm_alg::color::Rgb;
+use vdrm_alg::driver::PlainShiftRegister;
 use vdrm_alg::mirror_points_f;
 use web_sys::HtmlCanvasElement;
 
 static CTX: std::sync::Mutex<Option<Ctx>> = std::sync::Mutex::new(None);
 
-pub fn gen_rrds_surface() -> vdrm_alg::PixelSurface {
-    // const PATH: &'static str = "/Users/hikari/rust/vdrm-codec/frames/1738589846_rrds";
-    const SCREEN_WIDTH: usize = 256;
-    const SCREEN_HEIGHT: usize = 192;
-    const IMG_LEN: usize = SCREEN_HEIGHT * SCREEN_WIDTH + 1;
-    // let img = include_bytes!("/Users/hikari/rust/vdrm-codec/frames/1738589846_rrds");
-    let img = "";
-    let img: &[u64] = unsafe { std::slice::from_raw_parts(img.as_ptr() as *const u64, IMG_LEN) };
-
-    let mut pixel_surface = vdrm_alg::PixelSurface::new();
-
-    for y in 0..SCREEN_HEIGHT {
-        for x in 0..SCREEN_HEIGHT {
-            let idx = x + 32 + y * SCREEN_WIDTH + 1;
-            let pixel = img[idx];
-            let abgr = (pixel >> 32) as u32;
-            let rgba = abgr.swap_bytes();
-            let z = (pixel & 0xFFFF) as u32;
-            let z = (z as f32) / 0xFFFF as f32;
-            let mut z = ((1.0 - z) * 96.0) as u32;
-            if rgba & 0xFFFF_FF00 == 0 {
-                z = 0;
-            }
-            pixel_surface.push((x as u32, y as u32, (z, rgba)));
-        }
-    }
-    return pixel_surface;
-}
 pub fn gen_pyramid_surface() -> vdrm_alg::PixelSurface {
     let mut pixel_surface = vdrm_alg::PixelSurface::new();
     let r = vdrm_alg::W_PIXELS as i32 / 2;
@@ -47,13 +21,12 @@ pub fn gen_pyramid_surface() -> vdrm_alg::PixelSurface {
             if h >= 40 {
                 continue;
             }
-            let z = (r - 1 - h) as u32;
             let z = h as u32;
             let color = match (x_i32 >= 0, y_i32 >= 0) {
-                (true, true) => 0b111,
-                (false, true) => 0b001,
-                (false, false) => 0b010,
-                (true, false) => 0b101,
+                (true, true) => Rgb::WHITE,
+                (false, true) => Rgb::new(0xff, 0, 0),
+                (false, false) => Rgb::new(0, 0xff, 0),
+                (true, false) => Rgb::new(0xff, 0, 0xff),
             };
             pixel_surface.push((x, y, (z, color)));
         }
@@ -134,8 +107,7 @@ impl Ctx {
             .into_iter()
             .map(|(x, y, z)| (x, y + 1.0, -z))
             .collect();
-        let optimze_speed_for_mbi5264 = false;
-        let angle_map = codec.encode(&pixel_surface, 0, optimze_speed_for_mbi5264);
+        let angle_map = codec.encode(&pixel_surface, &PlainShiftRegister);
         let (mut all_emu_pixels, mut all_led_pixels) = (vec![], vec![]);
         let angle_ctx_map = (0..vdrm_alg::TOTAL_ANGLES as u32)
             .map(|angle| {
@@ -177,7 +149,7 @@ impl Ctx {
             all_real_pixels,
             all_emu_pixels,
             all_led_pixels,
-            screens: screens.map(|idx| Screen::new(idx)),
+            screens: screens.map(Screen::new),
             param,
         }
     }
@@ -265,7 +237,8 @@ pub fn draw(
         .screens
         .iter()
         .enumerate()
-        .filter_map(|(idx, v)| ctx.param.enb_screens.contains(&idx).then(|| v.polygon()));
+        .filter(|(idx, _)| ctx.param.enb_screens.contains(idx))
+        .map(|(_, v)| v.polygon());
     chart
         .draw_series(screen_polygons)?
         .label("SCREEN")
@@ -308,12 +281,15 @@ pub fn draw(
         });
 
     if let Some(angle) = angle {
-        let v_screens = ctx.screens.iter().enumerate().filter_map(|(idx, v)| {
-            ctx.param.enb_screens.contains(&idx).then(|| {
+        let v_screens = ctx
+            .screens
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| ctx.param.enb_screens.contains(idx))
+            .map(|(_, v)| {
                 let v_points = vdrm_alg::mirror_points(angle, &v.points);
                 Polygon::new(v_points, BLACK.mix(0.5))
-            })
-        });
+            });
         chart
             .draw_series(v_screens)?
             .label("V_SCREEN")
@@ -322,7 +298,7 @@ pub fn draw(
             });
     };
     let real_surface_points: PointSeries<_, _, Circle<_, _>, _> =
-        PointSeries::new(ctx.all_real_pixels.clone(), 1_f64, &BLUE.mix(0.2));
+        PointSeries::new(ctx.all_real_pixels.clone(), 1_f64, BLUE.mix(0.2));
     chart
         .draw_series(real_surface_points)?
         .label("REAL")
@@ -344,14 +320,14 @@ pub fn draw(
     };
 
     let emu_surface_points: PointSeries<_, _, Circle<_, _>, _> =
-        PointSeries::new(emu, 1_f32, &RED.mix(0.3));
+        PointSeries::new(emu, 1_f32, RED.mix(0.3));
     chart
         .draw_series(emu_surface_points)?
         .label("VIRTUAL")
         .legend(|(x, y)| Rectangle::new([(x + 5, y - 5), (x + 15, y + 5)], RED.mix(0.5).filled()));
 
     let led_surface_points: PointSeries<_, _, Circle<_, _>, _> =
-        PointSeries::new(led, 1_f64, &RED.mix(0.8));
+        PointSeries::new(led, 1_f64, RED.mix(0.8));
     chart.draw_series(led_surface_points)?;
 
     chart.configure_series_labels().border_style(BLACK).draw()?;